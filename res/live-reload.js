@@ -0,0 +1,23 @@
+// Added to HTML pages by tiny-serve --live-reload.
+(function () {
+    var source = new EventSource("/__tiny-serve/live-reload");
+    source.addEventListener("reload", function () {
+        location.reload();
+    });
+    // Stylesheets are swapped in place, so the page keeps its state.
+    source.addEventListener("css", function (event) {
+        var changed = event.data.split("\n");
+        var links = Array.prototype.filter.call(document.querySelectorAll("link[rel=stylesheet]"), function (link) {
+            return link.href;
+        });
+        var matching = links.filter(function (link) {
+            return changed.indexOf(new URL(link.href).pathname) !== -1;
+        });
+        // Imported stylesheets have no link of their own, so if none match, refresh them all.
+        (matching.length ? matching : links).forEach(function (link) {
+            var url = new URL(link.href);
+            url.searchParams.set("live-reload", Date.now());
+            link.href = url.href;
+        });
+    });
+})();
@@ -0,0 +1,43 @@
+use std::net::SocketAddr;
+
+use chrono::Local;
+
+use crate::http::{HTTPRequest, percent_encode};
+
+/*
+* One line of the access log, in Common Log Format:
+*     host ident authuser [date] "request-line" status bytes
+* Fields that are unknown are logged as "-". Bytes counts everything sent, head included.
+*/
+pub struct Entry {
+    peer: SocketAddr,
+    request_line: Option<String>,
+    pub user: Option<String>,
+}
+
+impl Entry {
+    pub fn new(peer: SocketAddr) -> Self {
+        Self {
+            peer,
+            request_line: None,
+            user: None,
+        }
+    }
+
+    // Record the request line, re-encoded so nothing decoded from the path ends up in the log.
+    pub fn request(&mut self, request: &HTTPRequest) {
+        let path: Vec<String> = request.requested_path.iter().map(|segment| percent_encode(segment)).collect();
+        let query = request.query.as_ref().map(|query| format!("?{}", query)).unwrap_or_default();
+        self.request_line = Some(format!("{} /{}{} {}", request.method.as_str(), path.join("/"), query, request.version.as_str()));
+    }
+
+    pub fn write(&self, status: u16, bytes: usize) {
+        println!("{} - {} [{}] \"{}\" {} {}",
+            self.peer.ip(),
+            self.user.as_deref().unwrap_or("-"),
+            Local::now().format("%d/%b/%Y:%H:%M:%S %z"),
+            self.request_line.as_deref().unwrap_or("-"),
+            status,
+            bytes);
+    }
+}
@@ -0,0 +1,716 @@
+use std::io;
+use std::mem;
+use std::net::IpAddr;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use async_std::fs::{self, File, OpenOptions};
+use async_std::os::unix::fs::OpenOptionsExt;
+use async_std::prelude::*;
+use chrono::{DateTime, Datelike, Local, Timelike};
+use crc32fast::Hasher;
+use flate2::Compression;
+use flate2::write::{DeflateEncoder, GzEncoder};
+use futures::stream;
+
+use crate::config::Config;
+use crate::http::{HTTPRequest, percent_encode};
+use crate::listing;
+use crate::response;
+
+const CHUNK_SIZE: usize = 64 * 1024;
+const DIRECTORY_MODE: u32 = 0o755;
+
+#[derive(Clone, Copy)]
+pub enum Format {
+    Zip,
+    TarGz,
+}
+
+impl Format {
+    // Selected with ?download=zip|tar.gz; anything else is a listing as usual.
+    pub fn from_request(request: &HTTPRequest) -> Option<Self> {
+        match request.query_parameter("download").as_deref() {
+            Some("zip") => Some(Format::Zip),
+            Some("tar.gz") => Some(Format::TarGz),
+            _ => None,
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            Format::Zip => "zip",
+            Format::TarGz => "tar.gz",
+        }
+    }
+
+    fn content_type(&self) -> &'static str {
+        match self {
+            Format::Zip => "application/zip",
+            Format::TarGz => "application/gzip",
+        }
+    }
+}
+
+/*
+* The directory at `path`, which is `requested` relative to `root`, as an archive in `format`.
+* It's made as it's sent, a chunk of one file at a time, so nothing is written to disk and memory
+* use doesn't grow with the files; only zip's central directory grows, with the number of entries.
+*/
+pub async fn download(format: Format, path: PathBuf, requested: PathBuf, request: &HTTPRequest, peer: IpAddr, root: &Path, config: &Arc<Config>) -> response::Ok {
+    // Everything goes in a directory named after the one downloaded, so it unpacks tidily.
+    let name = requested.file_name().or_else(|| root.file_name()).map_or_else(|| "root".to_owned(), |name| name.to_string_lossy().into_owned());
+    let filename = format!("{}.{}", name, format.extension());
+    // The plain filename is for clients that don't understand filename*.
+    let fallback: String = filename.chars().map(|c| if c.is_ascii_graphic() && c != '"' && c != '\\' { c } else { '_' }).collect();
+    let modified = fs::metadata(&path).await.and_then(|metadata| metadata.modified()).ok();
+    let archiver = Archiver {
+        config: config.clone(),
+        request: request.clone(),
+        peer,
+        root: root.to_path_buf(),
+        admitted: vec![requested.clone()],
+        pending: vec![Pending { modified, path, relative: requested, name, is_dir: true, parents: Arc::default() }],
+        file: None,
+        writer: match format {
+            Format::Zip => Box::new(Zip::default()),
+            Format::TarGz => Box::new(Tar::default()),
+        },
+        finished: false,
+    };
+    let file_stream = stream::unfold(Some(archiver), |archiver| async move {
+        let mut archiver = archiver?;
+        match archiver.next().await {
+            Ok(Some(chunk)) => Some((Ok(chunk), Some(archiver))),
+            Ok(None) => None,
+            // The client has part of an archive by now, which is all we can do for them.
+            Err(e) => Some((Err(e), None)),
+        }
+    });
+    response::Ok {
+        headers: vec![
+            ("Content-Type".to_owned(), format.content_type().to_owned()),
+            ("Content-Disposition".to_owned(), format!("attachment; filename=\"{}\"; filename*=UTF-8''{}", fallback, percent_encode(&filename))),
+        ],
+        file_stream: Box::new(Box::pin(file_stream)),
+        generated: false,
+    }
+}
+
+// An entry waiting to be archived.
+struct Pending {
+    path: PathBuf,
+    // Relative to the root, as it would be requested.
+    relative: PathBuf,
+    // In the archive.
+    name: String,
+    is_dir: bool,
+    modified: Option<SystemTime>,
+    // Canonical paths of the directories it's in, so links can't lead around in circles.
+    parents: Arc<Vec<PathBuf>>,
+}
+
+struct Archiver {
+    config: Arc<Config>,
+    request: HTTPRequest,
+    peer: IpAddr,
+    root: PathBuf,
+    // Directories the request has been found to be authorized for, starting with the one requested.
+    admitted: Vec<PathBuf>,
+    // Depth first, so the next entry is on top.
+    pending: Vec<Pending>,
+    // The file being archived, if we're partway through one, and how much of it is left to read.
+    file: Option<(File, u64)>,
+    writer: Box<dyn Writer + Send + Sync>,
+    finished: bool,
+}
+
+impl Archiver {
+    // The next piece of the archive, or None once it's complete.
+    async fn next(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            if let Some((file, remaining)) = &mut self.file {
+                // A file that grows while it's read ends where the header says, or it never would.
+                let mut chunk = vec![0; CHUNK_SIZE.min(*remaining as usize)];
+                let size = if chunk.is_empty() { 0 } else { file.read(&mut chunk).await? };
+                if size > 0 {
+                    *remaining -= size as u64;
+                    return self.writer.data(&chunk[..size]).map(Some);
+                }
+                self.file = None;
+                return self.writer.end_file().map(Some);
+            }
+            let pending = match self.pending.pop() {
+                Some(pending) => pending,
+                None if self.finished => return Ok(None),
+                None => {
+                    self.finished = true;
+                    return self.writer.finish().map(Some);
+                },
+            };
+            // Entries can disappear while we're busy with others, which isn't worth failing over.
+            if pending.is_dir {
+                let canonical: PathBuf = match fs::canonicalize(&pending.path).await {
+                    Ok(canonical) => canonical.into(),
+                    Err(_) => continue,
+                };
+                if pending.parents.contains(&canonical) {
+                    continue;
+                }
+                self.push_entries(&pending, canonical).await;
+                return self.writer.directory(&pending.name, pending.modified).map(Some);
+            }
+            // Without blocking, in case it's been swapped for a FIFO that no one will ever write to.
+            let file = match OpenOptions::new().read(true).custom_flags(libc::O_NONBLOCK).open(&pending.path).await {
+                Ok(file) => file,
+                Err(_) => continue,
+            };
+            let metadata = match file.metadata().await {
+                Ok(metadata) if metadata.is_file() => metadata,
+                _ => continue,
+            };
+            let header = self.writer.start_file(&pending.name, metadata.len(), metadata.permissions().mode() & 0o777, pending.modified)?;
+            self.file = Some((file, metadata.len()));
+            return Ok(Some(header));
+        }
+    }
+
+    // Queue up the entries of `directory` that the client could have downloaded one by one.
+    async fn push_entries(&mut self, directory: &Pending, canonical: PathBuf) {
+        let rules = self.config.policy.rules_for(&self.root, &directory.relative).await;
+        let entries = listing::read_entries(&directory.path, &directory.relative, &rules).await;
+        drop(rules);
+        let mut entries = match entries {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        // Popped off the end, so they're archived in order of name.
+        entries.sort_by(|a, b| b.name.cmp(&a.name));
+        let mut parents = directory.parents.as_ref().clone();
+        parents.push(canonical);
+        let parents = Arc::new(parents);
+        for entry in entries {
+            let path = directory.path.join(&entry.name);
+            // Devices, FIFOs and sockets don't have contents to archive, and reading them might never end.
+            if !entry.is_dir && !fs::metadata(&path).await.is_ok_and(|metadata| metadata.is_file()) {
+                continue;
+            }
+            let pending = Pending {
+                path,
+                relative: directory.relative.join(&entry.name),
+                name: format!("{}/{}", directory.name, entry.name),
+                is_dir: entry.is_dir,
+                modified: entry.modified,
+                parents: parents.clone(),
+            };
+            if self.permits(&pending).await {
+                self.pending.push(pending);
+            }
+        }
+    }
+
+    /*
+    * Whether the client is allowed `pending` by address and by authentication, as well as where
+    * it leads if it's a link. Authentication is only checked again where it could come out
+    * differently, so a realm's password isn't verified once per file.
+    */
+    async fn permits(&mut self, pending: &Pending) -> bool {
+        let mut paths = vec![pending.relative.clone()];
+        if let Ok(target) = fs::canonicalize(&pending.path).await {
+            if let Ok(relative) = Path::new(&target).strip_prefix(&self.root) {
+                paths.push(relative.to_path_buf());
+            }
+        }
+        for path in paths {
+            if !self.config.ip_filter.permits_path(self.peer, &path) {
+                return false;
+            }
+            if self.admitted.iter().any(|admitted| self.config.auth.same_realm(&path, admitted)) {
+                continue;
+            }
+            if self.config.auth.check(&path, &self.request).await.is_err() {
+                return false;
+            }
+            if pending.is_dir {
+                self.admitted.push(path);
+            }
+        }
+        true
+    }
+}
+
+// An archive format, fed one entry at a time. Each call returns the bytes of the archive it completed.
+trait Writer {
+    fn directory(&mut self, name: &str, modified: Option<SystemTime>) -> io::Result<Vec<u8>>;
+    fn start_file(&mut self, name: &str, size: u64, mode: u32, modified: Option<SystemTime>) -> io::Result<Vec<u8>>;
+    fn data(&mut self, data: &[u8]) -> io::Result<Vec<u8>>;
+    fn end_file(&mut self) -> io::Result<Vec<u8>>;
+    fn finish(&mut self) -> io::Result<Vec<u8>>;
+}
+
+// The largest number that fits in a tar header's 12-byte numeric fields.
+const TAR_MAX_NUMBER: u64 = 0o77777777777;
+
+/*
+* POSIX.1-2008, pax
+* A gzipped ustar archive. Names over 100 bytes and files over 8 GiB get a pax extended header.
+*/
+struct Tar {
+    gzip: GzEncoder<Vec<u8>>,
+    // Of the current file, as the header says; it may have changed size since.
+    size: u64,
+    remaining: u64,
+}
+
+impl Default for Tar {
+    fn default() -> Self {
+        Self {
+            gzip: GzEncoder::new(Vec::new(), Compression::default()),
+            size: 0,
+            remaining: 0,
+        }
+    }
+}
+
+impl Tar {
+    // Compress `data`, and return whatever compressed output is ready.
+    fn write(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        io::Write::write_all(&mut self.gzip, data)?;
+        Ok(mem::take(self.gzip.get_mut()))
+    }
+
+    fn entry(&mut self, name: &str, size: u64, mode: u32, modified: Option<SystemTime>, typeflag: u8) -> io::Result<Vec<u8>> {
+        let mut records = String::new();
+        if name.len() > 100 {
+            records.push_str(&pax_record("path", name));
+        }
+        if size > TAR_MAX_NUMBER {
+            records.push_str(&pax_record("size", &size.to_string()));
+        }
+        let modified = modified.and_then(|modified| modified.duration_since(UNIX_EPOCH).ok()).map_or(0, |modified| modified.as_secs());
+        let mut archive = Vec::new();
+        if !records.is_empty() {
+            archive.extend_from_slice(&ustar_header("././@PaxHeader", records.len() as u64, 0o644, modified, b'x'));
+            archive.extend_from_slice(records.as_bytes());
+            archive.resize(archive.len() + padding(records.len() as u64), 0);
+        }
+        archive.extend_from_slice(&ustar_header(name, size, mode, modified, typeflag));
+        self.write(&archive)
+    }
+}
+
+impl Writer for Tar {
+    fn directory(&mut self, name: &str, modified: Option<SystemTime>) -> io::Result<Vec<u8>> {
+        self.entry(&format!("{}/", name), 0, DIRECTORY_MODE, modified, b'5')
+    }
+
+    fn start_file(&mut self, name: &str, size: u64, mode: u32, modified: Option<SystemTime>) -> io::Result<Vec<u8>> {
+        self.size = size;
+        self.remaining = size;
+        self.entry(name, size, mode, modified, b'0')
+    }
+
+    fn data(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        // Anything past the size in the header would be taken for the next header.
+        let data = &data[..data.len().min(self.remaining as usize)];
+        self.remaining -= data.len() as u64;
+        self.write(data)
+    }
+
+    fn end_file(&mut self) -> io::Result<Vec<u8>> {
+        // A file that shrank is made up with zeros, and every file is padded to a whole block.
+        let mut zeros = self.remaining + padding(self.size) as u64;
+        let mut archive = Vec::new();
+        while zeros > 0 {
+            let size = zeros.min(CHUNK_SIZE as u64);
+            archive.extend(self.write(&vec![0; size as usize])?);
+            zeros -= size;
+        }
+        self.remaining = 0;
+        Ok(archive)
+    }
+
+    fn finish(&mut self) -> io::Result<Vec<u8>> {
+        let mut archive = self.write(&[0; 1024])?;
+        archive.extend(mem::take(self).gzip.finish()?);
+        Ok(archive)
+    }
+}
+
+// How many zeros bring `size` bytes up to a whole number of 512-byte blocks.
+fn padding(size: u64) -> usize {
+    ((512 - size % 512) % 512) as usize
+}
+
+// "%d %s=%s\n", where the length at the start counts itself.
+fn pax_record(key: &str, value: &str) -> String {
+    let record = format!(" {}={}\n", key, value);
+    let mut length = record.len();
+    while record.len() + length.to_string().len() != length {
+        length = record.len() + length.to_string().len();
+    }
+    format!("{}{}", length, record)
+}
+
+fn ustar_header(name: &str, size: u64, mode: u32, modified: u64, typeflag: u8) -> [u8; 512] {
+    let mut header = [0; 512];
+    // A longer name is in the pax header, so this only has to be something.
+    let mut end = name.len().min(100);
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    header[..end].copy_from_slice(&name.as_bytes()[..end]);
+    octal(&mut header[100..108], mode as u64);
+    octal(&mut header[108..116], 0);
+    octal(&mut header[116..124], 0);
+    octal(&mut header[124..136], size.min(TAR_MAX_NUMBER));
+    octal(&mut header[136..148], modified.min(TAR_MAX_NUMBER));
+    header[156] = typeflag;
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    // The checksum is worked out with its own field as spaces.
+    header[148..156].copy_from_slice(b"        ");
+    let checksum: u32 = header.iter().map(|byte| *byte as u32).sum();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+    header
+}
+
+// Zero-padded octal digits, with a NUL at the end.
+fn octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:01$o}", value, field.len() - 1);
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+}
+
+// PKWARE APPNOTE.TXT, 4.3.7: local file header.
+const ZIP_LOCAL_HEADER: u32 = 0x0403_4B50;
+// 4.3.9: data descriptor.
+const ZIP_DATA_DESCRIPTOR: u32 = 0x0807_4B50;
+// 4.3.12: central directory header.
+const ZIP_CENTRAL_HEADER: u32 = 0x0201_4B50;
+// 4.3.16: end of central directory record.
+const ZIP_END: u32 = 0x0605_4B50;
+// 4.4.4: sizes and checksums follow the data, and names are UTF-8.
+const ZIP_FLAG_DESCRIPTOR: u16 = 0x0008;
+const ZIP_FLAG_UTF8: u16 = 0x0800;
+const ZIP_STORED: u16 = 0;
+const ZIP_DEFLATED: u16 = 8;
+// 4.4.3: version 2.0, for deflate and directories.
+const ZIP_VERSION_NEEDED: u16 = 20;
+// 4.4.2: Unix, so the external attributes are taken as a mode.
+const ZIP_VERSION_MADE_BY: u16 = 3 << 8 | ZIP_VERSION_NEEDED;
+
+struct ZipEntry {
+    name: String,
+    is_dir: bool,
+    mode: u32,
+    time: (u16, u16),
+    // Of the local header.
+    offset: u64,
+    crc: Hasher,
+    size: u64,
+    compressed: u64,
+}
+
+impl ZipEntry {
+    fn flags(&self) -> u16 {
+        if self.is_dir { ZIP_FLAG_UTF8 } else { ZIP_FLAG_DESCRIPTOR | ZIP_FLAG_UTF8 }
+    }
+
+    fn method(&self) -> u16 {
+        if self.is_dir { ZIP_STORED } else { ZIP_DEFLATED }
+    }
+}
+
+/*
+* PKWARE APPNOTE.TXT
+* A zip file, deflated as it's written, with the sizes and checksum of each file after its data
+* since they aren't known until then. Without zip64, it's limited to 4 GiB and 65535 entries.
+*/
+#[derive(Default)]
+struct Zip {
+    // How much has been written, which is where the next entry starts.
+    offset: u64,
+    central_directory: Vec<u8>,
+    entries: u64,
+    // The file being written, if any, and its compressor.
+    file: Option<(ZipEntry, DeflateEncoder<Vec<u8>>)>,
+}
+
+impl Zip {
+    fn start_entry(&mut self, name: String, is_dir: bool, mode: u32, modified: Option<SystemTime>) -> io::Result<(ZipEntry, Vec<u8>)> {
+        if self.offset > u32::MAX as u64 || self.entries >= u16::MAX as u64 {
+            return Err(too_big_for_zip());
+        }
+        let entry = ZipEntry {
+            name,
+            is_dir,
+            mode,
+            time: dos_time(modified),
+            offset: self.offset,
+            crc: Hasher::new(),
+            size: 0,
+            compressed: 0,
+        };
+        let mut header = Vec::new();
+        put_32(&mut header, ZIP_LOCAL_HEADER);
+        put_16(&mut header, ZIP_VERSION_NEEDED);
+        put_16(&mut header, entry.flags());
+        put_16(&mut header, entry.method());
+        put_16(&mut header, entry.time.0);
+        put_16(&mut header, entry.time.1);
+        // The checksum and sizes come later, or are zero.
+        header.extend_from_slice(&[0; 12]);
+        put_16(&mut header, entry.name.len() as u16);
+        put_16(&mut header, 0);
+        header.extend_from_slice(entry.name.as_bytes());
+        Ok((entry, self.written(header)))
+    }
+
+    fn end_entry(&mut self, entry: &ZipEntry) {
+        let file_type = if entry.is_dir { 0o040000 } else { 0o100000 };
+        // 0x10 is the MS-DOS directory attribute.
+        let attributes = (file_type | entry.mode) << 16 | if entry.is_dir { 0x10 } else { 0 };
+        let header = &mut self.central_directory;
+        put_32(header, ZIP_CENTRAL_HEADER);
+        put_16(header, ZIP_VERSION_MADE_BY);
+        put_16(header, ZIP_VERSION_NEEDED);
+        put_16(header, entry.flags());
+        put_16(header, entry.method());
+        put_16(header, entry.time.0);
+        put_16(header, entry.time.1);
+        put_32(header, entry.crc.clone().finalize());
+        put_32(header, entry.compressed as u32);
+        put_32(header, entry.size as u32);
+        put_16(header, entry.name.len() as u16);
+        // No extra field, comment, disk number or internal attributes.
+        header.extend_from_slice(&[0; 8]);
+        put_32(header, attributes);
+        put_32(header, entry.offset as u32);
+        header.extend_from_slice(entry.name.as_bytes());
+        self.entries += 1;
+    }
+
+    fn written(&mut self, bytes: Vec<u8>) -> Vec<u8> {
+        self.offset += bytes.len() as u64;
+        bytes
+    }
+}
+
+impl Writer for Zip {
+    fn directory(&mut self, name: &str, modified: Option<SystemTime>) -> io::Result<Vec<u8>> {
+        let (entry, header) = self.start_entry(format!("{}/", name), true, DIRECTORY_MODE, modified)?;
+        self.end_entry(&entry);
+        Ok(header)
+    }
+
+    fn start_file(&mut self, name: &str, _: u64, mode: u32, modified: Option<SystemTime>) -> io::Result<Vec<u8>> {
+        let (entry, header) = self.start_entry(name.to_owned(), false, mode, modified)?;
+        self.file = Some((entry, DeflateEncoder::new(Vec::new(), Compression::default())));
+        Ok(header)
+    }
+
+    fn data(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let (file, deflater) = match &mut self.file {
+            Some(file) => file,
+            None => return Ok(Vec::new()),
+        };
+        io::Write::write_all(deflater, data)?;
+        let compressed = mem::take(deflater.get_mut());
+        file.crc.update(data);
+        file.size += data.len() as u64;
+        file.compressed += compressed.len() as u64;
+        Ok(self.written(compressed))
+    }
+
+    fn end_file(&mut self) -> io::Result<Vec<u8>> {
+        let (mut file, deflater) = match self.file.take() {
+            Some(file) => file,
+            None => return Ok(Vec::new()),
+        };
+        let mut archive = deflater.finish()?;
+        file.compressed += archive.len() as u64;
+        if file.size > u32::MAX as u64 || file.compressed > u32::MAX as u64 {
+            return Err(too_big_for_zip());
+        }
+        put_32(&mut archive, ZIP_DATA_DESCRIPTOR);
+        put_32(&mut archive, file.crc.clone().finalize());
+        put_32(&mut archive, file.compressed as u32);
+        put_32(&mut archive, file.size as u32);
+        self.end_entry(&file);
+        Ok(self.written(archive))
+    }
+
+    fn finish(&mut self) -> io::Result<Vec<u8>> {
+        if self.offset > u32::MAX as u64 {
+            return Err(too_big_for_zip());
+        }
+        let mut archive = mem::take(&mut self.central_directory);
+        let size = archive.len() as u32;
+        put_32(&mut archive, ZIP_END);
+        // Disk numbers.
+        put_32(&mut archive, 0);
+        put_16(&mut archive, self.entries as u16);
+        put_16(&mut archive, self.entries as u16);
+        put_32(&mut archive, size);
+        put_32(&mut archive, self.offset as u32);
+        // Comment length.
+        put_16(&mut archive, 0);
+        Ok(self.written(archive))
+    }
+}
+
+fn too_big_for_zip() -> io::Error {
+    io::Error::other("archive too big for zip; use tar.gz instead")
+}
+
+fn put_16(bytes: &mut Vec<u8>, value: u16) {
+    bytes.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_32(bytes: &mut Vec<u8>, value: u32) {
+    bytes.extend_from_slice(&value.to_le_bytes());
+}
+
+// PKWARE APPNOTE.TXT, 4.4.6: MS-DOS time and date, in local time, which can't go back before 1980.
+fn dos_time(modified: Option<SystemTime>) -> (u16, u16) {
+    let modified: DateTime<Local> = modified.unwrap_or(UNIX_EPOCH).into();
+    if modified.year() < 1980 {
+        return (0, (1 << 5) | 1);
+    }
+    let time = (modified.hour() << 11) | (modified.minute() << 5) | (modified.second() / 2);
+    let date = (((modified.year() - 1980).min(127) as u32) << 9) | (modified.month() << 5) | modified.day();
+    (time as u16, date as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::{DeflateDecoder, GzDecoder};
+
+    use super::*;
+
+    // An archive of a directory "d" holding `files`, as `writer` streams it.
+    fn archive(mut writer: impl Writer, files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut archive = writer.directory("d", None).unwrap();
+        for (name, data) in files {
+            archive.extend(writer.start_file(&format!("d/{}", name), data.len() as u64, 0o644, None).unwrap());
+            // In two chunks, as a file would be read.
+            let (first, second) = data.split_at(data.len() / 2);
+            archive.extend(writer.data(first).unwrap());
+            archive.extend(writer.data(second).unwrap());
+            archive.extend(writer.end_file().unwrap());
+        }
+        archive.extend(writer.finish().unwrap());
+        archive
+    }
+
+    fn number(field: &[u8]) -> u64 {
+        let digits = std::str::from_utf8(field).unwrap().trim_matches(|c| c == '\0' || c == ' ');
+        u64::from_str_radix(digits, 8).unwrap()
+    }
+
+    fn get_16(bytes: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes([bytes[at], bytes[at + 1]])
+    }
+
+    fn get_32(bytes: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+    }
+
+    #[test]
+    fn ustar_checksums_count_their_own_field_as_spaces() {
+        let header = ustar_header("d/a.txt", 1234, 0o644, 1_700_000_000, b'0');
+        let mut blank = header;
+        blank[148..156].copy_from_slice(b"        ");
+        assert_eq!(number(&header[148..156]), blank.iter().map(|byte| *byte as u64).sum::<u64>());
+        assert_eq!(&header[..8], b"d/a.txt\0");
+        assert_eq!(number(&header[100..108]), 0o644);
+        assert_eq!(number(&header[124..136]), 1234);
+        assert_eq!(number(&header[136..148]), 1_700_000_000);
+        assert_eq!(&header[257..265], b"ustar\x0000");
+    }
+
+    #[test]
+    fn pax_records_count_their_own_length() {
+        // Around where the length gains a digit.
+        for size in 0..1200 {
+            let record = pax_record("path", &"x".repeat(size));
+            let (length, rest) = record.split_once(' ').unwrap();
+            assert_eq!(length.parse::<usize>().unwrap(), record.len());
+            assert_eq!(rest, format!("path={}\n", "x".repeat(size)));
+        }
+    }
+
+    #[test]
+    fn tar_gz_round_trips() {
+        let long_name = "n".repeat(150);
+        let files: [(&str, &[u8]); 3] = [("a.txt", b"hello, world\n"), ("empty", b""), (&long_name, &[7; 1000])];
+        let mut tar = Vec::new();
+        GzDecoder::new(&archive(Tar::default(), &files)[..]).read_to_end(&mut tar).unwrap();
+        assert_eq!(tar.len() % 512, 0);
+        let mut blocks = tar.chunks(512);
+        let directory = blocks.next().unwrap();
+        assert_eq!(&directory[..3], b"d/\0");
+        assert_eq!(directory[156], b'5');
+        for (name, data) in files {
+            let mut header = blocks.next().unwrap();
+            let name = format!("d/{}", name);
+            if name.len() > 100 {
+                assert_eq!(header[156], b'x');
+                let records = blocks.next().unwrap();
+                let expected = pax_record("path", &name);
+                assert_eq!(number(&header[124..136]), expected.len() as u64);
+                assert_eq!(&records[..expected.len()], expected.as_bytes());
+                header = blocks.next().unwrap();
+            } else {
+                assert_eq!(&header[..name.len() + 1], format!("{}\0", name).as_bytes());
+            }
+            assert_eq!(header[156], b'0');
+            assert_eq!(number(&header[124..136]), data.len() as u64);
+            let mut contents = Vec::new();
+            for _ in 0..(data.len() + padding(data.len() as u64)) / 512 {
+                contents.extend_from_slice(blocks.next().unwrap());
+            }
+            assert_eq!(&contents[..data.len()], data);
+            assert!(contents[data.len()..].iter().all(|byte| *byte == 0));
+        }
+        // Two blocks of zeros end the archive.
+        assert!(blocks.all(|block| block.iter().all(|byte| *byte == 0)));
+    }
+
+    #[test]
+    fn zip_round_trips() {
+        let files: [(&str, &[u8]); 2] = [("a.txt", b"hello, world\n"), ("b.bin", &[7; 5000])];
+        let zip = archive(Zip::default(), &files);
+        let end = zip.len() - 22;
+        assert_eq!(get_32(&zip, end), ZIP_END);
+        assert_eq!(get_16(&zip, end + 10), 3);
+        let central_directory = get_32(&zip, end + 16) as usize;
+        assert_eq!(central_directory + get_32(&zip, end + 12) as usize, end);
+        // The directory, then each file, deflated and followed by a data descriptor.
+        let mut at = 0;
+        assert_eq!(get_32(&zip, at), ZIP_LOCAL_HEADER);
+        assert_eq!(get_16(&zip, at + 8), ZIP_STORED);
+        at += 30 + "d/".len();
+        for (name, data) in files {
+            assert_eq!(get_32(&zip, at), ZIP_LOCAL_HEADER);
+            assert_eq!(get_16(&zip, at + 8), ZIP_DEFLATED);
+            let name_length = get_16(&zip, at + 26) as usize;
+            assert_eq!(&zip[at + 30..at + 30 + name_length], format!("d/{}", name).as_bytes());
+            at += 30 + name_length;
+            let mut decoder = DeflateDecoder::new(&zip[at..]);
+            let mut contents = Vec::new();
+            decoder.read_to_end(&mut contents).unwrap();
+            assert_eq!(contents, data);
+            at += decoder.total_in() as usize;
+            assert_eq!(get_32(&zip, at), ZIP_DATA_DESCRIPTOR);
+            assert_eq!(get_32(&zip, at + 4), crc32fast::hash(data));
+            assert_eq!(get_32(&zip, at + 12) as usize, data.len());
+            at += 16;
+        }
+        assert_eq!(at, central_directory);
+    }
+}
@@ -0,0 +1,179 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+
+use crate::error::{Error, Result};
+use crate::http::{HTTPRequest, Method};
+use crate::policy;
+use crate::signing::SigningKey;
+use crate::tokens::Tokens;
+
+// The htpasswd hash formats we can verify: bcrypt, SHA-256-crypt and SHA-512-crypt.
+const SCHEMES: &[&str] = &["$2a$", "$2b$", "$2y$", "$5$", "$6$"];
+
+struct Realm {
+    // Relative to the root, as request paths are after normalization.
+    prefix: PathBuf,
+    name: String,
+    // User name to password hash.
+    users: HashMap<String, String>,
+}
+
+enum Credentials {
+    Basic(String, String),
+    Bearer(String),
+}
+
+/*
+* RFC 7617, RFC 6750
+* Authentication for parts of the tree. Each realm covers a path prefix and takes its users from an
+* htpasswd file; where realms are nested, the one with the longest prefix applies. Inside a realm,
+* a bearer token scoped to the path, or a signed link to it, does as well as a user's password.
+*/
+#[derive(Default)]
+pub struct Auth {
+    realms: Vec<Realm>,
+    pub tokens: Tokens,
+    pub signing_key: Option<SigningKey>,
+}
+
+impl Auth {
+    // Add a realm given as PREFIX=HTPASSWD or PREFIX=REALM=HTPASSWD.
+    pub fn add(&mut self, spec: &str) -> std::result::Result<(), String> {
+        let parts: Vec<&str> = spec.splitn(3, '=').collect();
+        let (prefix, name, file) = match parts[..] {
+            [prefix, file] => (prefix, "tiny-serve", file),
+            [prefix, name, file] => (prefix, name, file),
+            _ => return Err(format!("--auth: expected PREFIX=HTPASSWD or PREFIX=REALM=HTPASSWD, got {}", spec)),
+        };
+        let segments: Vec<String> = prefix.split('/').map(str::to_owned).collect();
+        let prefix = policy::normalize(&segments).map_err(|_| format!("--auth: {} leads outside the root", prefix))?;
+        let users = Self::read_htpasswd(Path::new(file)).map_err(|e| format!("--auth: {}: {}", file, e))?;
+        self.realms.push(Realm {
+            prefix,
+            name: name.to_owned(),
+            users,
+        });
+        Ok(())
+    }
+
+    // Lines of user:hash; blank lines and lines starting with '#' are skipped.
+    fn read_htpasswd(path: &Path) -> std::result::Result<HashMap<String, String>, String> {
+        let contents = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let mut users = HashMap::new();
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (user, hash) = line.split_once(':').ok_or_else(|| format!("line {}: expected user:hash", number + 1))?;
+            if !SCHEMES.iter().any(|scheme| hash.starts_with(scheme)) {
+                return Err(format!("line {}: unsupported hash for {}; use bcrypt, SHA-256 or SHA-512", number + 1, user));
+            }
+            users.insert(user.to_owned(), hash.to_owned());
+        }
+        Ok(users)
+    }
+
+    /*
+    * Check the credentials sent with `request` against the realm covering `path`, relative to the
+    * root. Returns the authenticated user or token, None if no realm covers the path or it was
+    * reached through a signed link, or Unauthorized with the challenges to send.
+    */
+    pub async fn check(&self, path: &Path, request: &HTTPRequest) -> Result<Option<String>> {
+        let realm = match self.realm(path) {
+            Some(realm) => realm,
+            None => return Ok(None),
+        };
+        let write = !matches!(request.method, Method::GET | Method::HEAD);
+        match Self::credentials(request) {
+            Some(Credentials::Basic(user, password)) => if let Some(hash) = realm.users.get(&user) {
+                let hash = hash.clone();
+                // bcrypt is slow by design, so keep it off the executor.
+                if blocking::unblock(move || pwhash::unix::verify(&password, &hash)).await {
+                    return Ok(Some(user));
+                }
+            },
+            Some(Credentials::Bearer(token)) => match self.tokens.get(&token) {
+                Some(token) if token.permits(path, write) => return Ok(Some(token.name.clone())),
+                /* RFC 6750, Page 10: a token without the scope asked for gets 403. */
+                Some(_) => return Err(Error::Forbidden),
+                None => return Err(self.unauthorized(realm, Some("invalid_token"))),
+            },
+            None => if !write && self.signed(request) {
+                return Ok(None);
+            },
+        }
+        Err(self.unauthorized(realm, None))
+    }
+
+    // Whether any realm covers `path`, so that credentials sent for it are ours.
+    pub fn covers(&self, path: &Path) -> bool {
+        self.realm(path).is_some()
+    }
+
+    // The realm covering `path`: the one with the longest prefix of it.
+    fn realm(&self, path: &Path) -> Option<&Realm> {
+        self.realms.iter().filter(|realm| path.starts_with(&realm.prefix)).max_by_key(|realm| realm.prefix.components().count())
+    }
+
+    /*
+    * Whether check() would come to the same answer for `a` and `b`, which it does when they're in
+    * the same realm and any token that covers `b` covers `a`, because `a` is under `b`.
+    */
+    pub fn same_realm(&self, a: &Path, b: &Path) -> bool {
+        let prefix = |path| self.realm(path).map(|realm| &realm.prefix);
+        a.starts_with(b) && prefix(a) == prefix(b)
+    }
+
+    /*
+    * RFC 7617, Page 4; RFC 6750, Page 5
+    * credentials = "Basic" 1*SP token68, where token68 is the Base64 encoding of user-id ":" password.
+    * credentials = "Bearer" 1*SP b64token
+    */
+    fn credentials(request: &HTTPRequest) -> Option<Credentials> {
+        let (scheme, token) = request.header("authorization")?.trim().split_once(' ')?;
+        let token = token.trim();
+        if scheme.eq_ignore_ascii_case("bearer") {
+            return Some(Credentials::Bearer(token.to_owned()));
+        }
+        if !scheme.eq_ignore_ascii_case("basic") {
+            return None;
+        }
+        let decoded = String::from_utf8(STANDARD.decode(token).ok()?).ok()?;
+        let (user, password) = decoded.split_once(':')?;
+        Some(Credentials::Basic(user.to_owned(), password.to_owned()))
+    }
+
+    // Whether the request carries a valid signature for the path it asks for.
+    fn signed(&self, request: &HTTPRequest) -> bool {
+        let (key, expires, signature) = match (&self.signing_key, request.query_parameter("expires"), request.query_parameter("sig")) {
+            (Some(key), Some(expires), Some(signature)) => (key, expires, signature),
+            _ => return false,
+        };
+        match policy::normalize(&request.requested_path) {
+            Ok(path) => key.verify(&format!("/{}", path.display()), &expires, &signature),
+            Err(_) => false,
+        }
+    }
+
+    // The WWW-Authenticate challenges for `realm`, with a Bearer error code if there is one.
+    fn unauthorized(&self, realm: &Realm, error: Option<&str>) -> Error {
+        let name = realm.name.replace('\\', "\\\\").replace('"', "\\\"");
+        let mut challenges = Vec::new();
+        // A realm without users is only there for tokens and signed links.
+        if !realm.users.is_empty() || self.tokens.is_empty() {
+            challenges.push(format!("Basic realm=\"{}\", charset=\"UTF-8\"", name));
+        }
+        if !self.tokens.is_empty() {
+            challenges.push(match error {
+                Some(error) => format!("Bearer realm=\"{}\", error=\"{}\"", name, error),
+                None => format!("Bearer realm=\"{}\"", name),
+            });
+        }
+        Error::Unauthorized(challenges)
+    }
+}
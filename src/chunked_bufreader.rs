@@ -1,3 +1,4 @@
+use std::io;
 use std::pin::Pin;
 
 use async_std::prelude::*;
@@ -26,7 +27,7 @@ impl<T> ChunkedBufReader<T>
 
 impl<T> Stream for ChunkedBufReader<T>
     where T: Read + Unpin {
-    type Item = Vec<u8>;
+    type Item = io::Result<Vec<u8>>;
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         let this = self.project();
         // This is quite wasteful, but perfomance is fine and the only real optimization (other
@@ -38,10 +39,10 @@ impl<T> Stream for ChunkedBufReader<T>
                     Poll::Ready(None)
                 } else {
                     chunk.truncate(size);
-                    Poll::Ready(Some(chunk))
+                    Poll::Ready(Some(Ok(chunk)))
                 }
             },
-            Poll::Ready(Err(_)) => Poll::Ready(None),
+            Poll::Ready(Err(e)) => Poll::Ready(Some(Err(e))),
             Poll::Pending => Poll::Pending,
         }
     }
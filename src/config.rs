@@ -0,0 +1,352 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::auth::Auth;
+use crate::cors::Cors;
+use crate::custom_headers::CustomHeaders;
+use crate::ip_filter::{Denial, IpFilter};
+use crate::listing::Templates;
+use crate::rate_limit::{LimitBy, RateLimiter, MIN_RATE};
+use crate::redirects::Redirects;
+use crate::security::{Preset, SecurityHeaders};
+use crate::vhosts::VirtualHosts;
+use crate::proxy::Proxy;
+use crate::live_reload::{self, LiveReload};
+use crate::websocket::{self, Routes};
+use crate::policy::{Policy, Symlinks};
+use crate::signing::SigningKey;
+use crate::tokens::Tokens;
+
+pub const USAGE: &str = "\
+Usage: tiny-serve [OPTIONS] [PORT]
+       tiny-serve sign --signing-key FILE [--expires DURATION] PATH
+
+Serves the current directory on PORT (default 8000).
+
+Options:
+    --index NAMES       Comma-separated file names to serve in place of a
+                        directory listing (default: index.html,index.htm).
+                        Pass an empty string to always list directories.
+    --no-listing        Refuse to list directories (403 Forbidden).
+    --error-pages DIR   Serve error pages from DIR when it has one for the
+                        status, e.g. 404.html, then 4xx.html, then
+                        error.html. {status}, {reason} and {path} are
+                        replaced in the page.
+    --templates DIR     Render HTML listings with listing.html and
+                        listing_entry.html from DIR, where present.
+                        listing.html may use {path}, {breadcrumbs},
+                        {downloads}, {entries}, {sort_name}, {sort_mtime},
+                        {sort_size} and {sort_type}; listing_entry.html may
+                        use {name}, {href}, {size}, {mtime}, {type},
+                        {extension} and {link}.
+    --symlinks MODE     Follow symbolic links only to targets under the root
+                        (root, the default), anywhere (any), or not at
+                        all (never). Refused links are logged and 404.
+    --show-hidden       List and serve files whose names start with a dot,
+                        and the _headers and _redirects files in the root.
+                        They are hidden and refused (404) by default.
+    --allow GLOB        Always list and serve paths matching GLOB, relative
+                        to the root. May be given more than once.
+    --deny GLOB         Hide and refuse paths matching GLOB, relative to the
+                        root. May be given more than once.
+    --gitignore         Hide and refuse paths ignored by .gitignore files.
+    --auth PREFIX=[REALM=]HTPASSWD
+                        Require HTTP Basic authentication for paths under
+                        PREFIX, checked against the users in the htpasswd
+                        file HTPASSWD (bcrypt, SHA-256 or SHA-512 hashes).
+                        REALM names the realm (default: tiny-serve). May
+                        be given more than once; the longest prefix wins.
+    --tokens FILE       Also admit bearer tokens listed in FILE, one per line
+                        as NAME TOKEN RIGHTS PREFIX..., where RIGHTS is read,
+                        write or read,write. A token only works under its
+                        prefixes, and only inside an --auth realm: paths no
+                        realm covers are open, so tokens aren't checked.
+    --signing-key FILE  Also admit links signed with the key in FILE, as made
+                        by the sign subcommand, until they expire.
+    --allow-from [PREFIX=]CIDR
+                        Only answer clients in the IPv4 or IPv6 block CIDR,
+                        e.g. 10.0.0.0/8, everywhere or for paths under
+                        PREFIX. May be given more than once.
+    --deny-from [PREFIX=]CIDR
+                        Refuse clients in CIDR, even if allowed, everywhere
+                        or for paths under PREFIX. May be given more than
+                        once.
+    --deny-action MODE  Answer refused clients with 403 Forbidden (forbid,
+                        the default) or close the connection (close).
+    --rate-limit N      Allow each client N requests per second, in bursts of
+                        up to N. Further requests get 429 Too Many Requests.
+                        N may be a fraction, down to 0.001.
+    --bandwidth-limit N Send each client at most N bytes per second, with an
+                        optional K, M or G suffix. Responses are slowed down,
+                        not refused.
+    --limit-by KEY      Count limits per address (ip, the default) or per
+                        authenticated user (user).
+    --cors-origin ORIGIN
+                        Let pages from ORIGIN read responses, e.g.
+                        http://localhost:3000. May contain * wildcards, as in
+                        https://*.example.com, or be just * for any origin.
+                        May be given more than once.
+    --cors-methods LIST Comma-separated methods cross-origin requests may use
+                        (default: GET,HEAD).
+    --cors-headers LIST Comma-separated request headers cross-origin requests
+                        may send, or * for any.
+    --cors-credentials  Let cross-origin requests send cookies and
+                        credentials.
+    --cors-max-age SECS How long browsers may cache preflight responses.
+    --security-headers PRESET
+                        Add hardening headers to responses: nothing (off, the
+                        default); nosniff, a referrer policy, framing only
+                        by the same origin and a strict Content-Security-
+                        Policy on listings and error pages (basic); or all
+                        of that plus cross-origin isolation, as needed for
+                        SharedArrayBuffer (isolated).
+    --hsts SECS         Send Strict-Transport-Security with a max-age of
+                        SECS, for when a proxy in front serves TLS.
+    --header 'GLOB Name: value'
+                        Send a header with responses to paths matching GLOB,
+                        e.g. '/assets/* Cache-Control: immutable'. May be
+                        given more than once. A _headers file in the root,
+                        with each GLOB on a line of its own followed by
+                        indented headers, is read as well.
+    --spa FILE          Serve FILE, e.g. /index.html, with 200 OK in place of
+                        a 404 for pages a browser asks for, as single-page
+                        apps with client-side routing need. Missing paths
+                        with an extension, and hidden ones, still get a
+                        404. FILE has to be in every root served from.
+    --vhost HOST=DIR    Serve requests for HOST from DIR rather than the
+                        current directory. HOST may be *.example.com for any
+                        subdomain. May be given more than once.
+    --default-host HOST Serve requests for unknown hosts as HOST, one of the
+                        --vhost hosts, rather than from the current directory.
+    --proxy PREFIX=UPSTREAM
+                        Forward requests for paths under PREFIX, e.g. /api,
+                        with their method, headers and body, to UPSTREAM,
+                        given as HOST:PORT or unix:PATH for a Unix socket.
+                        PREFIX is taken off, so /api/users goes upstream as
+                        /users. May be given more than once; the longest
+                        prefix wins.
+    --proxy-connect-timeout SECS
+                        Give up on connecting upstream after SECS seconds
+                        (default: 5), with 504 Gateway Timeout.
+    --proxy-read-timeout SECS
+                        Give up on an upstream that sends nothing for SECS
+                        seconds (default: 60).
+    --live-reload       Reload pages in the browser when files under the root
+                        change, or just their stylesheets when only CSS
+                        changed. HTML files get a script added to them for
+                        this. Changes are pushed as server-sent events, or
+                        over a WebSocket, at /__tiny-serve/live-reload.
+    --websocket-echo PATH
+                        Accept WebSockets at PATH and send every message back,
+                        for trying out clients.
+    -h, --help          Print this message.
+";
+
+pub struct Config {
+    pub port: u16,
+    pub index_files: Vec<String>,
+    pub listings: bool,
+    pub error_pages: Option<PathBuf>,
+    pub templates: Templates,
+    pub policy: Policy,
+    pub auth: Auth,
+    pub ip_filter: IpFilter,
+    pub rate_limiter: RateLimiter,
+    pub cors: Cors,
+    pub security: SecurityHeaders,
+    pub headers: CustomHeaders,
+    pub redirects: Redirects,
+    // Request segments of the file to serve for unknown pages, or empty if there's none.
+    pub spa_fallback: Vec<String>,
+    pub hosts: VirtualHosts,
+    pub proxy: Proxy,
+    pub live_reload: LiveReload,
+    pub websockets: Routes,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            port: 8000,
+            index_files: vec!["index.html".to_owned(), "index.htm".to_owned()],
+            listings: true,
+            error_pages: None,
+            templates: Templates::default(),
+            policy: Policy::default(),
+            auth: Auth::default(),
+            ip_filter: IpFilter::default(),
+            rate_limiter: RateLimiter::default(),
+            cors: Cors::default(),
+            security: SecurityHeaders::default(),
+            headers: CustomHeaders::default(),
+            redirects: Redirects::default(),
+            spa_fallback: Vec::new(),
+            hosts: VirtualHosts::default(),
+            proxy: Proxy::default(),
+            live_reload: LiveReload::default(),
+            websockets: Routes::default(),
+        }
+    }
+}
+
+impl Config {
+    pub fn from_args<I>(mut args: I) -> Result<Self, String>
+        where I: Iterator<Item = String> {
+        let mut config = Self::default();
+        let mut symlinks = Symlinks::Root;
+        let mut show_hidden = false;
+        let mut allow = Vec::new();
+        let mut deny = Vec::new();
+        let mut gitignore = false;
+        let mut default_host = None;
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--index" => {
+                    config.index_files = Vec::new();
+                    for name in Self::value(&mut args, &arg)?.split(',').filter(|name| !name.is_empty()) {
+                        if name.contains('/') || name == "." || name == ".." {
+                            return Err(format!("--index: {} is not a file name", name));
+                        }
+                        config.index_files.push(name.to_owned());
+                    }
+                },
+                "--no-listing" => config.listings = false,
+                "--error-pages" => config.error_pages = Some(Self::directory(&mut args, &arg)?),
+                "--templates" => config.templates = Templates::load(&Self::directory(&mut args, &arg)?)?,
+                "--symlinks" => {
+                    let mode = Self::value(&mut args, &arg)?;
+                    symlinks = Symlinks::from_str(&mode).ok_or_else(|| format!("--symlinks: unknown mode {}", mode))?;
+                },
+                "--show-hidden" => show_hidden = true,
+                "--allow" => allow.push(Self::value(&mut args, &arg)?),
+                "--deny" => deny.push(Self::value(&mut args, &arg)?),
+                "--gitignore" => gitignore = true,
+                "--auth" => config.auth.add(&Self::value(&mut args, &arg)?)?,
+                "--tokens" => {
+                    let file = Self::value(&mut args, &arg)?;
+                    config.auth.tokens = Tokens::load(Path::new(&file)).map_err(|e| format!("--tokens: {}: {}", file, e))?;
+                },
+                "--signing-key" => {
+                    let file = Self::value(&mut args, &arg)?;
+                    config.auth.signing_key = Some(SigningKey::load(Path::new(&file)).map_err(|e| format!("--signing-key: {}: {}", file, e))?);
+                },
+                "--allow-from" => config.ip_filter.add(&Self::value(&mut args, &arg)?, true).map_err(|e| format!("{}: {}", arg, e))?,
+                "--deny-from" => config.ip_filter.add(&Self::value(&mut args, &arg)?, false).map_err(|e| format!("{}: {}", arg, e))?,
+                "--deny-action" => {
+                    let mode = Self::value(&mut args, &arg)?;
+                    config.ip_filter.denial = Denial::from_str(&mode).ok_or_else(|| format!("--deny-action: unknown mode {}", mode))?;
+                },
+                "--rate-limit" => {
+                    let rate = Self::value(&mut args, &arg)?;
+                    config.rate_limiter.requests_per_second = Some(Self::rate(&rate, 1).ok_or_else(|| format!("--rate-limit: invalid rate {}", rate))?);
+                },
+                "--bandwidth-limit" => {
+                    let rate = Self::value(&mut args, &arg)?;
+                    let (number, unit) = match rate.char_indices().last() {
+                        Some((i, 'K')) => (&rate[..i], 1 << 10),
+                        Some((i, 'M')) => (&rate[..i], 1 << 20),
+                        Some((i, 'G')) => (&rate[..i], 1 << 30),
+                        _ => (rate.as_str(), 1),
+                    };
+                    config.rate_limiter.bytes_per_second = Some(Self::rate(number, unit).ok_or_else(|| format!("--bandwidth-limit: invalid rate {}", rate))?);
+                },
+                "--limit-by" => {
+                    let key = Self::value(&mut args, &arg)?;
+                    config.rate_limiter.limit_by = LimitBy::from_str(&key).ok_or_else(|| format!("--limit-by: unknown key {}", key))?;
+                },
+                "--cors-origin" => config.cors.add_origin(&Self::value(&mut args, &arg)?).map_err(|e| format!("--cors-origin: {}", e))?,
+                "--cors-methods" => config.cors.methods = Self::list(&Self::value(&mut args, &arg)?, |method| method.to_ascii_uppercase()),
+                "--cors-headers" => config.cors.headers = Self::list(&Self::value(&mut args, &arg)?, |header| header.to_ascii_lowercase()),
+                "--cors-credentials" => config.cors.credentials = true,
+                "--cors-max-age" => {
+                    let max_age = Self::value(&mut args, &arg)?;
+                    config.cors.max_age = Some(max_age.parse().map_err(|_| format!("--cors-max-age: invalid number of seconds {}", max_age))?);
+                },
+                "--security-headers" => {
+                    let preset = Self::value(&mut args, &arg)?;
+                    config.security.preset = Preset::from_str(&preset).ok_or_else(|| format!("--security-headers: unknown preset {}", preset))?;
+                },
+                "--hsts" => {
+                    let max_age = Self::value(&mut args, &arg)?;
+                    config.security.hsts = Some(max_age.parse().map_err(|_| format!("--hsts: invalid number of seconds {}", max_age))?);
+                },
+                "--header" => config.headers.add(&Self::value(&mut args, &arg)?).map_err(|e| format!("--header: {}", e))?,
+                "--spa" => {
+                    let file = Self::value(&mut args, &arg)?;
+                    if file.split('/').any(|segment| segment == "..") {
+                        return Err(format!("--spa: {} leads outside the root", file));
+                    }
+                    config.spa_fallback = file.split('/').filter(|segment| !segment.is_empty()).map(str::to_owned).collect();
+                },
+                "--vhost" => config.hosts.add(&Self::value(&mut args, &arg)?).map_err(|e| format!("--vhost: {}", e))?,
+                "--default-host" => default_host = Some(Self::value(&mut args, &arg)?),
+                "--proxy" => config.proxy.add(&Self::value(&mut args, &arg)?).map_err(|e| format!("--proxy: {}", e))?,
+                "--proxy-connect-timeout" => config.proxy.connect_timeout = Self::seconds(&mut args, &arg)?,
+                "--proxy-read-timeout" => config.proxy.read_timeout = Self::seconds(&mut args, &arg)?,
+                "--live-reload" => {
+                    config.live_reload.enabled = true;
+                    config.websockets.add(live_reload::EVENTS_PATH, live_reload::changes);
+                },
+                "--websocket-echo" => {
+                    let path = Self::value(&mut args, &arg)?;
+                    if !path.starts_with('/') {
+                        return Err(format!("--websocket-echo: {} is not a path", path));
+                    }
+                    config.websockets.add(&path, websocket::echo);
+                },
+                option if option.starts_with('-') => return Err(format!("unknown option {}", option)),
+                // An invalid port has always meant the default one.
+                port => config.port = port.parse::<u16>().unwrap_or(8000),
+            }
+        }
+        // Hosts may be given in any order, so the default is only checked once they're all known.
+        if let Some(default_host) = default_host {
+            config.hosts.set_default(&default_host).map_err(|e| format!("--default-host: {}", e))?;
+        }
+        // Likewise the fallback, which every root that can be served from has to have.
+        if !config.spa_fallback.is_empty() {
+            let file: PathBuf = config.spa_fallback.iter().collect();
+            for root in config.hosts.served_roots() {
+                if !root.join(&file).is_file() {
+                    return Err(format!("--spa: /{} is not a file in {}", file.display(), root.display()));
+                }
+            }
+        }
+        config.policy = Policy::new(symlinks, show_hidden, &allow, &deny, gitignore)?;
+        Ok(config)
+    }
+
+    fn value<I>(args: &mut I, option: &str) -> Result<String, String>
+        where I: Iterator<Item = String> {
+        args.next().ok_or_else(|| format!("{} requires a value", option))
+    }
+
+    // The non-empty items of a comma-separated list.
+    fn list(list: &str, normalize: fn(&str) -> String) -> Vec<String> {
+        list.split(',').map(str::trim).filter(|item| !item.is_empty()).map(normalize).collect()
+    }
+
+    // A number per second, times `unit`, of at least MIN_RATE.
+    fn rate(number: &str, unit: u64) -> Option<f64> {
+        number.parse::<f64>().ok().map(|rate| rate * unit as f64).filter(|rate| rate.is_finite() && *rate >= MIN_RATE)
+    }
+
+    // A positive, possibly fractional, number of seconds.
+    fn seconds<I>(args: &mut I, option: &str) -> Result<Duration, String>
+        where I: Iterator<Item = String> {
+        let seconds = Self::value(args, option)?;
+        seconds.parse::<f64>().ok()
+            .filter(|seconds| *seconds > 0.0)
+            .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+            .ok_or_else(|| format!("{}: invalid number of seconds {}", option, seconds))
+    }
+
+    fn directory<I>(args: &mut I, option: &str) -> Result<PathBuf, String>
+        where I: Iterator<Item = String> {
+        let directory = PathBuf::from(Self::value(args, option)?);
+        if !directory.is_dir() {
+            return Err(format!("{}: {} is not a directory", option, directory.display()));
+        }
+        Ok(directory)
+    }
+}
@@ -0,0 +1,175 @@
+use globset::{Glob, GlobMatcher};
+
+use crate::http::{HTTPRequest, Method};
+use crate::response::{NoContent, add_header};
+
+/*
+* Cross-Origin Resource Sharing (Fetch Standard, section 3.2). Browsers only let pages from allowed
+* origins read our responses; origins are matched exactly, or as patterns with * wildcards, e.g.
+* for any subdomain. Nothing is sent unless at least one origin is allowed.
+*/
+pub struct Cors {
+    origins: Vec<GlobMatcher>,
+    // Whether any origin is allowed, so "*" can be sent without echoing.
+    any_origin: bool,
+    pub methods: Vec<String>,
+    // Lowercase, or "*" for any.
+    pub headers: Vec<String>,
+    pub credentials: bool,
+    pub max_age: Option<u64>,
+}
+
+impl Default for Cors {
+    fn default() -> Self {
+        Self {
+            origins: Vec::new(),
+            any_origin: false,
+            methods: vec!["GET".to_owned(), "HEAD".to_owned()],
+            headers: Vec::new(),
+            credentials: false,
+            max_age: None,
+        }
+    }
+}
+
+impl Cors {
+    pub fn add_origin(&mut self, origin: &str) -> Result<(), String> {
+        if origin == "*" {
+            self.any_origin = true;
+        }
+        self.origins.push(Glob::new(origin).map_err(|e| e.to_string())?.compile_matcher());
+        Ok(())
+    }
+
+    // The value of Access-Control-Allow-Origin for the request, if its origin is allowed.
+    fn allow_origin(&self, request: &HTTPRequest) -> Option<String> {
+        let origin = request.header("origin")?;
+        if !self.origins.iter().any(|pattern| pattern.is_match(origin)) {
+            return None;
+        }
+        // "*" doesn't work with credentials, so those get their origin echoed back.
+        if self.any_origin && !self.credentials {
+            Some("*".to_owned())
+        } else {
+            Some(origin.to_owned())
+        }
+    }
+
+    fn headers(&self, allow_origin: String) -> Vec<(String, String)> {
+        let mut headers = Vec::new();
+        if allow_origin != "*" {
+            // Caches have to keep responses for different origins apart.
+            headers.push(("Vary".to_owned(), "Origin".to_owned()));
+        }
+        headers.push(("Access-Control-Allow-Origin".to_owned(), allow_origin));
+        if self.credentials {
+            headers.push(("Access-Control-Allow-Credentials".to_owned(), "true".to_owned()));
+        }
+        headers
+    }
+
+    /*
+    * Answer a preflight request: an OPTIONS request with Origin and Access-Control-Request-Method.
+    * If the method and headers asked for are all allowed, the response says so; otherwise it
+    * says nothing, and the browser won't send the actual request.
+    */
+    pub fn preflight(&self, request: &HTTPRequest) -> Option<NoContent> {
+        if self.origins.is_empty() || !matches!(request.method, Method::OPTIONS) {
+            return None;
+        }
+        let method = request.header("access-control-request-method")?;
+        // The answer depends on what's asked for, so caches mustn't reuse it for other requests.
+        let mut response = NoContent { headers: vec![("Vary".to_owned(), "Access-Control-Request-Method, Access-Control-Request-Headers".to_owned())] };
+        let allow_origin = match self.allow_origin(request) {
+            Some(allow_origin) => allow_origin,
+            None => {
+                response.headers.push(("Vary".to_owned(), "Origin".to_owned()));
+                return Some(response);
+            },
+        };
+        let requested_headers: Vec<String> = request.header("access-control-request-headers").unwrap_or("")
+            .split(',')
+            .map(|header| header.trim().to_ascii_lowercase())
+            .filter(|header| !header.is_empty())
+            .collect();
+        let any_header = self.headers.iter().any(|header| header == "*");
+        if !self.methods.iter().any(|allowed| allowed == method)
+            || !requested_headers.iter().all(|header| any_header || self.headers.contains(header)) {
+            return Some(response);
+        }
+        response.headers.extend(self.headers(allow_origin));
+        response.headers.push(("Access-Control-Allow-Methods".to_owned(), self.methods.join(", ")));
+        if !requested_headers.is_empty() {
+            response.headers.push(("Access-Control-Allow-Headers".to_owned(), requested_headers.join(", ")));
+        }
+        if let Some(max_age) = self.max_age {
+            response.headers.push(("Access-Control-Max-Age".to_owned(), max_age.to_string()));
+        }
+        Some(response)
+    }
+
+    /*
+    * Add the headers that let the requesting origin read an actual response, in place of any a
+    * proxied server sent, since browsers refuse a response that allows more than one origin.
+    */
+    pub fn apply(&self, request: &HTTPRequest, headers: &mut Vec<(String, String)>) {
+        // Preflight responses already have theirs.
+        if matches!(request.method, Method::OPTIONS) {
+            return;
+        }
+        if let Some(allow_origin) = self.allow_origin(request) {
+            headers.retain(|(name, _)| !name.get(..15).is_some_and(|prefix| prefix.eq_ignore_ascii_case("access-control-")));
+            for (name, value) in self.headers(allow_origin) {
+                // It may already vary by Origin, too.
+                let listed = |(existing, values): &(String, String)| existing.eq_ignore_ascii_case(&name)
+                    && values.split(',').any(|listed| listed.trim().eq_ignore_ascii_case(&value));
+                if name != "Vary" || !headers.iter().any(listed) {
+                    add_header(headers, name, value);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::io::BufReader;
+    use async_std::task;
+
+    use super::*;
+    use crate::http::Parser;
+    use crate::peekable_bufreader::PeekableBufReader;
+
+    // The headers a response with `headers` goes out with, for a request from `origin`.
+    fn applied(origin: &str, headers: &[(&str, &str)]) -> Vec<(String, String)> {
+        let mut cors = Cors::default();
+        cors.add_origin("http://localhost:3000").unwrap();
+        let message = format!("GET / HTTP/1.1\r\nHost: localhost\r\nOrigin: {}\r\n\r\n", origin);
+        let mut parser = Parser::new(PeekableBufReader::new(BufReader::new(message.as_bytes())));
+        let request = task::block_on(parser.parse()).expect("the request should parse");
+        let mut headers = headers.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+        cors.apply(&request, &mut headers);
+        headers
+    }
+
+    #[test]
+    fn upstream_cors_headers_are_replaced() {
+        let headers = applied("http://localhost:3000", &[
+            ("access-control-allow-origin", "*"),
+            ("Access-Control-Allow-Credentials", "true"),
+            ("Vary", "Origin"),
+            ("Content-Type", "text/plain"),
+        ]);
+        assert_eq!(headers, vec![
+            ("Vary".to_owned(), "Origin".to_owned()),
+            ("Content-Type".to_owned(), "text/plain".to_owned()),
+            ("Access-Control-Allow-Origin".to_owned(), "http://localhost:3000".to_owned()),
+        ]);
+    }
+
+    #[test]
+    fn other_origins_are_left_alone() {
+        let headers = applied("http://example.com", &[("Access-Control-Allow-Origin", "*")]);
+        assert_eq!(headers, vec![("Access-Control-Allow-Origin".to_owned(), "*".to_owned())]);
+    }
+}
@@ -0,0 +1,98 @@
+use std::path::Path;
+
+use globset::{Glob, GlobMatcher};
+
+use crate::watched_file::WatchedFile;
+
+// Read from the root, like Netlify's.
+pub const HEADERS_FILE: &str = "_headers";
+
+struct Rule {
+    pattern: GlobMatcher,
+    headers: Vec<(String, String)>,
+}
+
+impl Rule {
+    fn new(pattern: &str) -> Result<Self, String> {
+        Ok(Self {
+            pattern: Glob::new(pattern).map_err(|e| e.to_string())?.compile_matcher(),
+            headers: Vec::new(),
+        })
+    }
+}
+
+// "Name: value", with a name made of token characters (RFC 7230, Page 27).
+fn parse_header(line: &str) -> Result<(String, String), String> {
+    let (name, value) = line.split_once(':').ok_or_else(|| format!("expected Name: value, got {}", line))?;
+    let name = name.trim();
+    if name.is_empty() || !name.bytes().all(|byte| byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte)) {
+        return Err(format!("{} is not a header name", name));
+    }
+    Ok((name.to_owned(), value.trim().to_owned()))
+}
+
+/*
+* Extra header fields for responses to paths matching glob patterns, from the command line and from
+* a _headers file in the root, which is read again whenever it changes. In the file, each pattern
+* starts a line and is followed by its headers, indented, one "Name: value" per line. Where rules
+* set the same header, the last one wins, and the file's rules come after the command line's.
+*/
+pub struct CustomHeaders {
+    configured: Vec<Rule>,
+    file: WatchedFile<Vec<Rule>>,
+}
+
+impl Default for CustomHeaders {
+    fn default() -> Self {
+        Self {
+            configured: Vec::new(),
+            file: WatchedFile::new(HEADERS_FILE, Self::parse),
+        }
+    }
+}
+
+impl CustomHeaders {
+    // Add a rule given as "PATTERN Name: value".
+    pub fn add(&mut self, spec: &str) -> Result<(), String> {
+        let (pattern, header) = spec.trim().split_once(char::is_whitespace).ok_or_else(|| format!("expected PATTERN Name: value, got {}", spec))?;
+        let mut rule = Rule::new(pattern)?;
+        rule.headers.push(parse_header(header)?);
+        self.configured.push(rule);
+        Ok(())
+    }
+
+    // Invalid lines are reported and skipped, so one typo doesn't lose every rule.
+    fn parse(path: &Path, contents: &str) -> Vec<Rule> {
+        let mut rules: Vec<Rule> = Vec::new();
+        for (number, line) in contents.lines().enumerate() {
+            let trimmed = line.trim();
+            if trimmed.is_empty() || trimmed.starts_with('#') {
+                continue;
+            }
+            let result = if line.starts_with(char::is_whitespace) {
+                match rules.last_mut() {
+                    Some(rule) => parse_header(trimmed).map(|header| rule.headers.push(header)),
+                    None => Err("a header before any pattern".to_owned()),
+                }
+            } else {
+                Rule::new(trimmed).map(|rule| rules.push(rule))
+            };
+            if let Err(e) = result {
+                eprintln!("{}: line {}: {}", path.display(), number + 1, e);
+            }
+        }
+        rules
+    }
+
+    // Set the headers for `path`, as requested, replacing any of the same name.
+    pub async fn apply(&self, root: &Path, path: &str, headers: &mut Vec<(String, String)>) {
+        let file = self.file.get(root).await;
+        let from_file = file.iter().flat_map(|rules| rules.iter());
+        for rule in self.configured.iter().chain(from_file).filter(|rule| rule.pattern.is_match(path)) {
+            for (name, value) in &rule.headers {
+                headers.retain(|(existing, _)| !existing.eq_ignore_ascii_case(name));
+                headers.push((name.clone(), value.clone()));
+            }
+        }
+    }
+}
@@ -0,0 +1,126 @@
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+use crate::http::{ParseError, ErrorKind};
+use crate::response::Status;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    // The request couldn't be parsed.
+    Parse(ParseError),
+    // Something went wrong talking to the filesystem or the client.
+    Io(io::Error),
+    // The requested resource doesn't exist, or we're pretending it doesn't.
+    NotFound,
+    // The requested resource exists, but the policy keeps it out of sight; answered like NotFound.
+    Hidden,
+    // The resource needs credentials, and none valid were sent. Holds the challenges to send.
+    Unauthorized(Vec<String>),
+    // We won't talk to this client at all.
+    Refused,
+    // The client has used up its requests for now, and can try again in the given number of seconds.
+    TooManyRequests(u64),
+    // The resource exists, but we won't serve it.
+    Forbidden,
+    // The request parsed, but asks for something nonsensical.
+    BadRequest,
+    // The request uses a method we don't serve.
+    NotImplemented,
+    // The request asks to switch to a protocol version we don't speak. Holds the header fields
+    // naming the ones we do.
+    UpgradeRequired(Vec<(String, String)>),
+    // A proxied request got no valid response from upstream, for the given reason.
+    BadGateway(String),
+    // A proxied request's upstream took too long to connect or to answer.
+    GatewayTimeout,
+    // The request leads through a symbolic link we won't follow, for the given reason.
+    SymlinkRefused(PathBuf, &'static str),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Parse(e) => write!(f, "parse error: {}", e),
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::NotFound => f.write_str("not found"),
+            Error::Hidden => f.write_str("hidden"),
+            Error::Unauthorized(_) => f.write_str("unauthorized"),
+            Error::Refused => f.write_str("refused"),
+            Error::TooManyRequests(_) => f.write_str("too many requests"),
+            Error::Forbidden => f.write_str("forbidden"),
+            Error::BadRequest => f.write_str("bad request"),
+            Error::NotImplemented => f.write_str("not implemented"),
+            Error::UpgradeRequired(_) => f.write_str("upgrade required"),
+            Error::BadGateway(reason) => write!(f, "bad gateway: {}", reason),
+            Error::GatewayTimeout => f.write_str("upstream timed out"),
+            Error::SymlinkRefused(path, reason) => write!(f, "refusing to follow {}: {}", path.display(), reason),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl Error {
+    // Whether the client hung up on us, in which case there's no one left to respond to.
+    pub fn is_disconnect(&self) -> bool {
+        match self {
+            Error::Parse(e) => e.kind == ErrorKind::ConnectionClosed,
+            Error::Io(e) => matches!(e.kind(), io::ErrorKind::BrokenPipe | io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted),
+            _ => false,
+        }
+    }
+
+    // The status to respond with, or None if there's no one left to respond to, or shouldn't be.
+    pub fn status(&self) -> Option<Status> {
+        if self.is_disconnect() {
+            return None;
+        }
+        Some(match self {
+            Error::Parse(error) => match error.kind {
+                ErrorKind::UnsupportedVersion => Status::HTTPVersionNotSupported,
+                ErrorKind::Oversized if error.rule.is_request_line() => Status::URITooLong,
+                ErrorKind::Oversized => Status::RequestHeaderFieldsTooLarge,
+                ErrorKind::Malformed | ErrorKind::BadTarget | ErrorKind::ConnectionClosed => Status::BadRequest,
+            },
+            Error::Io(e) if e.kind() == io::ErrorKind::NotFound => Status::NotFound,
+            Error::Io(e) if e.kind() == io::ErrorKind::PermissionDenied => Status::Forbidden,
+            Error::Io(_) => Status::InternalServerError,
+            Error::NotFound | Error::Hidden | Error::SymlinkRefused(..) => Status::NotFound,
+            Error::Unauthorized(_) => Status::Unauthorized,
+            Error::Refused => return None,
+            Error::TooManyRequests(_) => Status::TooManyRequests,
+            Error::Forbidden => Status::Forbidden,
+            Error::BadRequest => Status::BadRequest,
+            Error::NotImplemented => Status::NotImplemented,
+            Error::UpgradeRequired(_) => Status::UpgradeRequired,
+            Error::BadGateway(_) => Status::BadGateway,
+            Error::GatewayTimeout => Status::GatewayTimeout,
+        })
+    }
+
+    // Header fields the error response has to carry.
+    pub fn headers(&self) -> Vec<(String, String)> {
+        match self {
+            /* RFC 7235, Page 6: a 401 response MUST send a WWW-Authenticate header field. */
+            Error::Unauthorized(challenges) => challenges.iter().map(|challenge| ("WWW-Authenticate".to_owned(), challenge.clone())).collect(),
+            Error::TooManyRequests(seconds) => vec![("Retry-After".to_owned(), seconds.to_string())],
+            Error::UpgradeRequired(headers) => headers.clone(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+impl From<ParseError> for Error {
+    fn from(error: ParseError) -> Self {
+        Error::Parse(error)
+    }
+}
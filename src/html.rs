@@ -0,0 +1,44 @@
+// Escape text for use in HTML content and quoted attribute values.
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for character in text.chars() {
+        match character {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            character => escaped.push(character),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::escape;
+
+    #[test]
+    fn plain_text_is_unchanged() {
+        assert_eq!(escape("report 2024.pdf"), "report 2024.pdf");
+        assert_eq!(escape(""), "");
+        assert_eq!(escape("ünïcödé"), "ünïcödé");
+    }
+
+    #[test]
+    fn markup_is_escaped() {
+        assert_eq!(escape("<img src=x onerror=alert(1)>"), "&lt;img src=x onerror=alert(1)&gt;");
+        assert_eq!(escape("a & b"), "a &amp; b");
+    }
+
+    #[test]
+    fn quotes_are_escaped_for_attributes() {
+        assert_eq!(escape(r#"" onclick="x"#), "&quot; onclick=&quot;x");
+        assert_eq!(escape("' onclick='x"), "&#39; onclick=&#39;x");
+    }
+
+    #[test]
+    fn entities_are_escaped_again() {
+        assert_eq!(escape("&amp;"), "&amp;amp;");
+    }
+}
@@ -0,0 +1,120 @@
+/*
+* RFC 3986, Page 12
+* Decode a percent-encoded string. As in application/x-www-form-urlencoded, '+' stands for a
+* space. Fails on malformed escapes and on anything that doesn't decode to UTF-8.
+*/
+pub fn percent_decode(encoded: &str) -> Option<String> {
+    decode(encoded, true)
+}
+
+// As percent_decode, but for path segments, where '+' is just a '+'.
+pub fn percent_decode_segment(encoded: &str) -> Option<String> {
+    decode(encoded, false)
+}
+
+fn decode(encoded: &str, plus_is_space: bool) -> Option<String> {
+    let mut decoded = Vec::with_capacity(encoded.len());
+    let mut bytes = encoded.bytes();
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'%' => {
+                let high_word = (bytes.next()? as char).to_digit(16)?;
+                let low_word = (bytes.next()? as char).to_digit(16)?;
+                decoded.push((high_word * 16 + low_word) as u8);
+            },
+            b'+' if plus_is_space => decoded.push(b' '),
+            _ => decoded.push(byte),
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/*
+* RFC 3986, Page 12
+* Encode everything but unreserved characters, so the result is safe to use as a single path
+* segment, and just as safe inside an HTML attribute.
+*/
+pub fn percent_encode(decoded: &str) -> String {
+    let mut encoded = String::with_capacity(decoded.len());
+    for byte in decoded.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::io::BufReader;
+    use async_std::task;
+
+    use super::*;
+    use crate::http::Parser;
+    use crate::peekable_bufreader::PeekableBufReader;
+
+    // The segments the parser makes of a request for `target`.
+    fn parse_segments(target: &str) -> Vec<String> {
+        let message = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", target);
+        let mut parser = Parser::new(PeekableBufReader::new(BufReader::new(message.as_bytes())));
+        task::block_on(parser.parse()).expect("the request should parse").requested_path
+    }
+
+    // Encoding a name and requesting it should get the parser back to the same name.
+    fn assert_round_trip(name: &str) {
+        assert_eq!(parse_segments(&format!("/{}", percent_encode(name))), vec![name.to_owned()]);
+        assert_eq!(percent_decode_segment(&percent_encode(name)).as_deref(), Some(name));
+    }
+
+    #[test]
+    fn unreserved_characters_are_left_alone() {
+        assert_eq!(percent_encode("AZaz09-._~"), "AZaz09-._~");
+        assert_round_trip("AZaz09-._~");
+    }
+
+    #[test]
+    fn reserved_characters_round_trip() {
+        assert_eq!(percent_encode("a b#c?d"), "a%20b%23c%3Fd");
+        assert_round_trip(":@!$&'()*+,;=");
+        assert_round_trip("a b#c?d[e]");
+        assert_round_trip("<img onerror=alert(1)>.txt");
+    }
+
+    #[test]
+    fn percent_round_trips() {
+        assert_eq!(percent_encode("100%"), "100%25");
+        assert_round_trip("100%");
+        assert_round_trip("%41");
+    }
+
+    #[test]
+    fn slash_stays_inside_the_segment() {
+        assert_eq!(percent_encode("a/b"), "a%2Fb");
+        assert_round_trip("a/b");
+        assert_round_trip("/");
+    }
+
+    #[test]
+    fn non_ascii_round_trips() {
+        assert_eq!(percent_encode("ü"), "%C3%BC");
+        assert_round_trip("ünïcödé");
+        assert_round_trip("日本語.txt");
+        assert_round_trip("🦀");
+    }
+
+    #[test]
+    fn plus_is_only_a_space_in_queries() {
+        assert_eq!(percent_decode_segment("a+b").as_deref(), Some("a+b"));
+        assert_eq!(percent_decode("a+b").as_deref(), Some("a b"));
+        assert_round_trip("a+b");
+    }
+
+    #[test]
+    fn malformed_escapes_fail() {
+        assert_eq!(percent_decode_segment("%"), None);
+        assert_eq!(percent_decode_segment("%4"), None);
+        assert_eq!(percent_decode_segment("%zz"), None);
+        assert_eq!(percent_decode_segment("%FF"), None);
+    }
+}
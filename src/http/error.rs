@@ -0,0 +1,87 @@
+use std::fmt;
+
+/*
+* The grammar rules of RFC 7230 and RFC 3986 that the parser implements.
+*/
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rule {
+    RequestLine,
+    Method,
+    AbsolutePath,
+    Segment,
+    Query,
+    HTTPVersion,
+    HeaderField,
+    FieldName,
+}
+
+impl Rule {
+    pub fn is_request_line(&self) -> bool {
+        match self {
+            Rule::RequestLine |
+            Rule::Method |
+            Rule::AbsolutePath |
+            Rule::Segment |
+            Rule::Query |
+            Rule::HTTPVersion => true,
+            Rule::HeaderField |
+            Rule::FieldName => false,
+        }
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Rule::RequestLine => "request-line",
+            Rule::Method => "method",
+            Rule::AbsolutePath => "absolute-path",
+            Rule::Segment => "segment",
+            Rule::Query => "query",
+            Rule::HTTPVersion => "HTTP-version",
+            Rule::HeaderField => "header-field",
+            Rule::FieldName => "field-name",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorKind {
+    // The message doesn't match the grammar.
+    Malformed,
+    // The request-target isn't in origin-form, or contains invalid percent-encoding.
+    BadTarget,
+    // The HTTP-version is well-formed, but not one we speak.
+    UnsupportedVersion,
+    // The request-line or header section exceeds our size limits.
+    Oversized,
+    // The client went away before sending a complete message.
+    ConnectionClosed,
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ErrorKind::Malformed => "malformed",
+            ErrorKind::BadTarget => "bad request target",
+            ErrorKind::UnsupportedVersion => "unsupported version",
+            ErrorKind::Oversized => "oversized",
+            ErrorKind::ConnectionClosed => "connection closed",
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ParseError {
+    pub offset: usize,
+    pub rule: Rule,
+    pub kind: ErrorKind,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} at byte {}", self.kind, self.rule, self.offset)
+    }
+}
+
+impl std::error::Error for ParseError {}
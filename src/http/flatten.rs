@@ -1,8 +1,9 @@
 use std::collections::HashMap;
 
 use super::rule::{HTTPMessage, Method};
+use super::encoding::percent_decode;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Version {
     Http0_9,
     Http1_0,
@@ -10,28 +11,111 @@ pub enum Version {
     // HTTP/2 won't parse, anyway.
 }
 
-#[derive(Debug)]
+impl Version {
+    pub fn from_numbers(major: u32, minor: u32) -> Option<Version> {
+        match (major, minor) {
+            (0, 9) => Some(Version::Http0_9),
+            (1, 0) => Some(Version::Http1_0),
+            (1, 1) => Some(Version::Http1_1),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Version::Http0_9 => "HTTP/0.9",
+            Version::Http1_0 => "HTTP/1.0",
+            Version::Http1_1 => "HTTP/1.1",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct HTTPRequest {
     pub method: Method,
     pub version: Version,
     pub requested_path: Vec<String>,
+    // The same segments as sent, still percent-encoded, for passing the path on as it is.
+    pub raw_path: Vec<String>,
+    // Still percent-encoded; see query_parameter().
+    pub query: Option<String>,
+    // Keyed by lowercase field name, since field names are case-insensitive. Where a field is
+    // repeated, the last one wins.
     pub headers: HashMap<String, Vec<u8>>,
+    // Every field line, in order and with its name as sent, for passing the request on.
+    pub fields: Vec<(String, Vec<u8>)>,
 }
 
 pub fn flatten(message: HTTPMessage) -> Option<HTTPRequest> {
     let method = message.request_line.method;
-    let version = match (message.request_line.http_version.major, message.request_line.http_version.minor) {
-        (0, 9) => Version::Http0_9,
-        (1, 0) => Version::Http1_0,
-        (1, 1) => Version::Http1_1,
-        _ => return None,
-    };
-    let requested_path = message.request_line.request_target.absolute_path.segments.into_iter().map(|segment| segment.lexeme).collect();
-    let headers = message.header_fields.into_iter().map(|field| (field.name.lexeme, field.value.content)).collect();
+    let version = Version::from_numbers(message.request_line.http_version.major, message.request_line.http_version.minor)?;
+    let (requested_path, raw_path) = message.request_line.request_target.absolute_path.segments.into_iter().map(|segment| (segment.lexeme, segment.raw)).unzip();
+    let query = message.request_line.request_target.query.map(|query| query.lexeme);
+    let fields: Vec<(String, Vec<u8>)> = message.header_fields.into_iter().map(|field| (field.name.lexeme, field.value.content)).collect();
+    let headers = fields.iter().map(|(name, value)| (name.to_ascii_lowercase(), value.clone())).collect();
     Some(HTTPRequest{
         method,
         version,
         requested_path,
+        raw_path,
+        query,
         headers,
+        fields,
     })
 }
+
+impl HTTPRequest {
+    // The decoded path, as requested.
+    pub fn path(&self) -> String {
+        format!("/{}", self.requested_path.join("/"))
+    }
+
+    // The value of the header field called `name`, if present and valid UTF-8.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        std::str::from_utf8(self.headers.get(&name.to_ascii_lowercase())?).ok()
+    }
+
+    /*
+    * RFC 7231, Page 38
+    * The quality the client assigns to `media_type`, taken from the most specific media range in
+    * the Accept header that matches it. No Accept header means anything goes.
+    */
+    pub fn accept_quality(&self, media_type: &str) -> f32 {
+        let accept = match self.header("accept") {
+            Some(accept) => accept,
+            None => return 1.0,
+        };
+        let subtype_wildcard = format!("{}/*", media_type.split('/').next().unwrap_or(media_type));
+        let mut best: Option<(u8, f32)> = None;
+        for media_range in accept.split(',') {
+            let mut parameters = media_range.split(';');
+            let range = parameters.next().unwrap_or("").trim().to_ascii_lowercase();
+            let specificity = if range == media_type {
+                2
+            } else if range == subtype_wildcard {
+                1
+            } else if range == "*/*" {
+                0
+            } else {
+                continue;
+            };
+            let quality = parameters
+                .filter_map(|parameter| parameter.trim().strip_prefix("q="))
+                .find_map(|quality| quality.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            if best.is_none_or(|(best_specificity, _)| specificity > best_specificity) {
+                best = Some((specificity, quality));
+            }
+        }
+        best.map_or(0.0, |(_, quality)| quality)
+    }
+
+    // The decoded value of the first query parameter called `name`, if any.
+    pub fn query_parameter(&self, name: &str) -> Option<String> {
+        self.query.as_ref()?
+            .split('&')
+            .map(|parameter| parameter.split_once('=').unwrap_or((parameter, "")))
+            .find(|(key, _)| percent_decode(key).as_deref() == Some(name))
+            .and_then(|(_, value)| percent_decode(value))
+    }
+}
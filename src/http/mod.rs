@@ -1,6 +1,11 @@
 mod parser;
 mod rule;
 mod flatten;
+mod error;
+mod encoding;
 
 pub use parser::Parser;
+pub use flatten::{HTTPRequest, Version};
 pub use rule::Method;
+pub use error::{ParseError, ErrorKind};
+pub use encoding::{percent_encode, percent_decode_segment};
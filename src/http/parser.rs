@@ -1,7 +1,8 @@
 use async_std::io::Read;
 
-use super::rule::{HTTPMessage, HeaderField, FieldName, FieldValue, FieldContent, RequestLine, Method, OriginForm, HTTPVersion, AbsolutePath, Query, Segment};
-use super::flatten::{flatten, HTTPRequest};
+use super::rule::{HTTPMessage, HeaderField, FieldName, FieldValue, RequestLine, Method, OriginForm, HTTPVersion, AbsolutePath, Query, Segment};
+use super::flatten::{flatten, HTTPRequest, Version};
+use super::error::{ParseError, ErrorKind, Rule};
 use crate::peekable_bufreader::PeekableBufReader;
 
 const HTAB: u8 = 0x09;
@@ -13,10 +14,10 @@ const COLON: u8 = 0x3A;
 const QUESTION_MARK: u8 = 0x3F;
 const ATSIGN: u8 = 0x40;
 
-enum ErrorType {
-    Missing,
-    Malformed,
-}
+// Anything longer than this gets a 414 URI Too Long.
+const MAX_REQUEST_LINE_LENGTH: usize = 8192;
+// Anything longer than this gets a 431 Request Header Fields Too Large.
+const MAX_HEADER_SECTION_LENGTH: usize = 16384;
 
 pub struct Parser<T>
     where T: Read + Unpin {
@@ -31,26 +32,57 @@ impl<T> Parser<T>
         }
     }
 
-    pub async fn parse(self) -> Option<HTTPRequest> {
-        flatten(self.http_message().await?)
+    pub async fn parse(&mut self) -> Result<HTTPRequest, ParseError> {
+        let message = self.http_message().await?;
+        // http_version() has already rejected anything flatten() can't represent.
+        flatten(message).ok_or(ParseError {
+            offset: 0,
+            rule: Rule::HTTPVersion,
+            kind: ErrorKind::UnsupportedVersion,
+        })
+    }
+
+    // The source, positioned just after the header section.
+    pub fn into_inner(self) -> PeekableBufReader<T> {
+        self.source
+    }
+
+    /*
+    * Build an error at the current position. Running into the size limit or the end of the
+    * stream takes precedence over whatever the failing rule thinks went wrong, since that is
+    * almost certainly why it failed.
+    */
+    fn error(&self, rule: Rule, kind: ErrorKind) -> ParseError {
+        let kind = if self.source.is_over_limit() {
+            ErrorKind::Oversized
+        } else if self.source.is_exhausted() {
+            ErrorKind::ConnectionClosed
+        } else {
+            kind
+        };
+        ParseError {
+            offset: self.source.position(),
+            rule,
+            kind,
+        }
     }
 
     /*
     * RFC 7230, Page 19
     */
-    async fn http_message(mut self) -> Option<HTTPMessage> {
+    async fn http_message(&mut self) -> Result<HTTPMessage, ParseError> {
+        self.source.set_limit(MAX_REQUEST_LINE_LENGTH);
         let start_line = self.start_line().await?;
+        self.source.set_limit(self.source.position() + MAX_HEADER_SECTION_LENGTH);
         let mut header_fields = Vec::new();
-        loop {
-            if let Some(_) = self.consume_carriage_return().await {
-                break;
-            }
+        while self.consume_carriage_return().await.is_none() {
             header_fields.push(self.header_field().await?);
-            self.consume_carriage_return().await?;
+            self.consume_carriage_return().await.ok_or_else(|| self.error(Rule::HeaderField, ErrorKind::Malformed))?;
         }
         // GET Requests don't have a message body, and we only really deal with GET requests.
-        // There's no need to examine the headers and attempt to read a message body.
-        Some(HTTPMessage {
+        // There's no need to examine the headers and attempt to read a message body; whatever
+        // does want one, like a proxied request, can take it from into_inner().
+        Ok(HTTPMessage {
             request_line: start_line,
             header_fields,
         })
@@ -59,13 +91,13 @@ impl<T> Parser<T>
     /*
     * RFC 7230, Page 23
     */
-    async fn header_field(&mut self) -> Option<HeaderField> {
+    async fn header_field(&mut self) -> Result<HeaderField, ParseError> {
         let name = self.field_name().await?;
-        self.consume_char(&COLON).await?;
+        self.consume_char(&COLON).await.ok_or_else(|| self.error(Rule::HeaderField, ErrorKind::Malformed))?;
         self.consume_optional_whitespace().await;
         let value = self.field_value().await?;
         self.consume_optional_whitespace().await;
-        Some(HeaderField {
+        Ok(HeaderField {
             name,
             value,
         })
@@ -74,9 +106,9 @@ impl<T> Parser<T>
     /*
     * RFC 7230, Page 23
     */
-    async fn field_name(&mut self) -> Option<FieldName> {
-        Some(FieldName {
-            lexeme: self.logical_token().await?,
+    async fn field_name(&mut self) -> Result<FieldName, ParseError> {
+        Ok(FieldName {
+            lexeme: self.logical_token().await.ok_or_else(|| self.error(Rule::FieldName, ErrorKind::Malformed))?,
         })
     }
 
@@ -84,43 +116,38 @@ impl<T> Parser<T>
     * RFC 7230, Page 23
     * obs-fold is deprecated except within message/http media. This isn't going to come up for us,
     * so we deviate from the grammar slightly.
+    * field-content as written only allows a single run of whitespace per field-content, with no
+    * whitespace between adjacent field-contents (RFC 7230, Erratum 4189). We follow the intent
+    * instead: any run of whitespace between visible characters belongs to the value.
     */
-    async fn field_value(&mut self) -> Option<FieldValue> {
+    async fn field_value(&mut self) -> Result<FieldValue, ParseError> {
         let mut content = Vec::new();
-        loop {
-            // Look, no obs-fold!
-            if let Some(value) = self.field_content().await {
-                // Let's do some pre-emptive flattening here.
-                content.push(value.first_char);
-                if let Some(second_char) = value.second_char {
-                    content.push(SPACE);
-                    content.push(second_char);
+        // Look, no obs-fold!
+        while let Some(first_char) = self.field_vchar().await {
+            content.push(first_char);
+            loop {
+                if let Some(next_char) = self.field_vchar().await {
+                    content.push(next_char);
+                } else if self.consume_required_whitespace().await.is_some() {
+                    // Trailing whitespace is optional whitespace, not part of the value.
+                    match self.field_vchar().await {
+                        Some(next_char) => {
+                            // Let's do some pre-emptive flattening here.
+                            content.push(SPACE);
+                            content.push(next_char);
+                        },
+                        None => break,
+                    }
+                } else {
+                    break;
                 }
-            } else {
-                break;
             }
         }
-        Some(FieldValue {
+        Ok(FieldValue {
             content,
         })
     }
 
-    /*
-    * RFC 7230, Page 23
-    */
-    async fn field_content(&mut self) -> Option<FieldContent> {
-        let first_char = self.field_vchar().await?;
-        let second_char = if let Some(_) = self.consume_required_whitespace().await {
-            Some(self.field_vchar().await?)
-        } else {
-            None
-        };
-        Some(FieldContent{
-            first_char,
-            second_char,
-        })
-    }
-
     async fn field_vchar(&mut self) -> Option<u8> {
         let next_char = self.source.peek().await?;
         if Self::is_visible_char(next_char) || Self::is_obs_text_char(next_char) {
@@ -133,21 +160,21 @@ impl<T> Parser<T>
     * RFC 7230, Page 21
     * This is a server, so the start-line is exclusively a request-line.
     */
-    async fn start_line(&mut self) -> Option<RequestLine> {
+    async fn start_line(&mut self) -> Result<RequestLine, ParseError> {
         self.request_line().await
     }
-    
+
     /*
     * RFC 7230, Page 21
     */
-    async fn request_line(&mut self) -> Option<RequestLine> {
+    async fn request_line(&mut self) -> Result<RequestLine, ParseError> {
         let method = self.method().await?;
-        self.consume_char(&SPACE).await?;
+        self.consume_char(&SPACE).await.ok_or_else(|| self.error(Rule::RequestLine, ErrorKind::Malformed))?;
         let request_target = self.request_target().await?;
-        self.consume_char(&SPACE).await?;
+        self.consume_char(&SPACE).await.ok_or_else(|| self.error(Rule::RequestLine, ErrorKind::Malformed))?;
         let http_version = self.http_version().await?;
-        self.consume_carriage_return().await?;
-        Some(RequestLine {
+        self.consume_carriage_return().await.ok_or_else(|| self.error(Rule::RequestLine, ErrorKind::Malformed))?;
+        Ok(RequestLine {
             method,
             request_target,
             http_version,
@@ -158,21 +185,21 @@ impl<T> Parser<T>
     * RFC 7230, Page 41
     * We only serve some static content; therefore we only need support origin-form.
     */
-    async fn request_target(&mut self) -> Option<OriginForm> {
+    async fn request_target(&mut self) -> Result<OriginForm, ParseError> {
         self.origin_form().await
     }
 
     /*
     * RFC 7230, Page 42
     */
-    async fn origin_form(&mut self) -> Option<OriginForm> {
+    async fn origin_form(&mut self) -> Result<OriginForm, ParseError> {
         let absolute_path = self.absolute_path().await?;
-        let query = if let Some(_) = self.consume_char(&QUESTION_MARK).await {
+        let query = if self.consume_char(&QUESTION_MARK).await.is_some() {
             Some(self.query().await?)
         } else {
             None
         };
-        Some(OriginForm {
+        Ok(OriginForm {
             absolute_path,
             query,
         })
@@ -181,21 +208,14 @@ impl<T> Parser<T>
     /*
     * RFC 7230, Page 16
     */
-    async fn absolute_path(&mut self) -> Option<AbsolutePath> {
+    async fn absolute_path(&mut self) -> Result<AbsolutePath, ParseError> {
         let mut segments = Vec::new();
-        self.consume_char(&SLASH).await?;
+        self.consume_char(&SLASH).await.ok_or_else(|| self.error(Rule::AbsolutePath, ErrorKind::BadTarget))?;
         segments.push(self.segment().await?);
-        loop {
-            if let None = self.consume_char(&SLASH).await {
-                break;
-            }
-            if let Some(segment) = self.segment().await {
-                segments.push(segment);
-            } else {
-                return None;
-            }
+        while self.consume_char(&SLASH).await.is_some() {
+            segments.push(self.segment().await?);
         }
-        Some(AbsolutePath {
+        Ok(AbsolutePath {
             segments,
         })
     }
@@ -203,33 +223,49 @@ impl<T> Parser<T>
     /*
     * RFC 3986, Page 23
     */
-    async fn segment(&mut self) -> Option<Segment> {
+    async fn segment(&mut self) -> Result<Segment, ParseError> {
         let mut segment = Vec::new();
-        while self.source.peek().await.is_some() {
-            match self.consume_path_character().await {
-                Ok(character) => segment.push(character as char),
-                Err(ErrorType::Missing) => break,
-                Err(ErrorType::Malformed) => return None,
+        let mut raw = String::new();
+        loop {
+            // An encoded delimiter isn't the delimiter itself, so the encoding is kept as well.
+            if let Some(character) = self.consume_percent_encoded(Rule::Segment).await? {
+                raw.push_str(&format!("%{:02X}", character));
+                segment.push(character);
+            } else if let Some(character) = self.consume_path_character().await? {
+                raw.push(character as char);
+                segment.push(character);
+            } else {
+                break;
             }
         }
-        Some(Segment{
-            lexeme: segment.into_iter().collect(),
+        // Percent-encoded octets are UTF-8, as far as any file name we could serve is concerned.
+        Ok(Segment{
+            lexeme: String::from_utf8(segment).map_err(|_| self.error(Rule::Segment, ErrorKind::BadTarget))?,
+            raw,
         })
     }
 
     /*
     * RFC 3986, Page 50
+    * Unlike segments, the query is kept percent-encoded: an encoded '&' or '=' must not be
+    * mistaken for a delimiter when the query is later split into parameters.
     */
-    async fn query(&mut self) -> Option<Query> {
+    async fn query(&mut self) -> Result<Query, ParseError> {
         let mut query = Vec::new();
-        while self.source.peek().await.is_some() {
-            match self.consume_query_character().await {
-                Ok(character) => query.push(character as char),
-                Err(ErrorType::Missing) => break,
-                Err(ErrorType::Malformed) => return None,
+        loop {
+            if let Some(character) = self.consume_query_character().await {
+                query.push(character as char);
+            } else if self.consume_char(&PERCENT).await.is_some() {
+                let high_word = self.consume_hex_digit().await.ok_or_else(|| self.error(Rule::Query, ErrorKind::BadTarget))?;
+                let low_word = self.consume_hex_digit().await.ok_or_else(|| self.error(Rule::Query, ErrorKind::BadTarget))?;
+                query.push(PERCENT as char);
+                query.push(high_word as char);
+                query.push(low_word as char);
+            } else {
+                break;
             }
         }
-        Some(Query{
+        Ok(Query{
             lexeme: query.into_iter().collect(),
         })
     }
@@ -237,23 +273,32 @@ impl<T> Parser<T>
     /*
     * RFC 7230, Page 14
     */
-    async fn http_version(&mut self) -> Option<HTTPVersion> {
-        self.consume_logical_token("HTTP").await?;
-        self.consume_char(&SLASH).await?;
-        let major = Self::ascii_digit_to_value(&self.consume_digit().await?);
-        self.consume_char(&DOT).await?;
-        let minor = Self::ascii_digit_to_value(&self.consume_digit().await?);
-        Some(HTTPVersion{
+    async fn http_version(&mut self) -> Result<HTTPVersion, ParseError> {
+        let start = self.source.position();
+        self.consume_logical_token("HTTP").await.ok_or_else(|| self.error(Rule::HTTPVersion, ErrorKind::Malformed))?;
+        self.consume_char(&SLASH).await.ok_or_else(|| self.error(Rule::HTTPVersion, ErrorKind::Malformed))?;
+        let major = Self::ascii_digit_to_value(&self.consume_digit().await.ok_or_else(|| self.error(Rule::HTTPVersion, ErrorKind::Malformed))?);
+        self.consume_char(&DOT).await.ok_or_else(|| self.error(Rule::HTTPVersion, ErrorKind::Malformed))?;
+        let minor = Self::ascii_digit_to_value(&self.consume_digit().await.ok_or_else(|| self.error(Rule::HTTPVersion, ErrorKind::Malformed))?);
+        if Version::from_numbers(major, minor).is_none() {
+            return Err(ParseError {
+                offset: start,
+                rule: Rule::HTTPVersion,
+                kind: ErrorKind::UnsupportedVersion,
+            });
+        }
+        Ok(HTTPVersion{
             major,
             minor,
         })
     }
-    
+
     /*
     * RFC 7230, Page 21
     */
-    async fn method(&mut self) -> Option<Method> {
-        Method::from_string(&self.logical_token().await?)
+    async fn method(&mut self) -> Result<Method, ParseError> {
+        let token = self.logical_token().await.ok_or_else(|| self.error(Rule::Method, ErrorKind::Malformed))?;
+        Ok(Method::from_string(&token))
     }
 
     /*
@@ -264,12 +309,20 @@ impl<T> Parser<T>
         if !Self::is_logical_token_char(self.source.peek().await?) {
             return None;
         }
-        while self.source.peek().await.is_some() && Self::is_logical_token_char(self.source.peek().await.unwrap()) {
-            logical_token.push(self.source.next().await.unwrap() as char);
+        while let Some(character) = self.consume_logical_token_char().await {
+            logical_token.push(character as char);
         }
         Some(logical_token.into_iter().collect())
     }
 
+    async fn consume_logical_token_char(&mut self) -> Option<u8> {
+        let next_char = self.source.peek().await?;
+        if Self::is_logical_token_char(next_char) {
+            return self.source.next().await;
+        }
+        None
+    }
+
     async fn consume_char(&mut self, character: &u8) -> Option<u8> {
         let next_char = self.source.peek().await?;
         if *next_char == *character {
@@ -277,7 +330,7 @@ impl<T> Parser<T>
         }
         None
     }
-    
+
     async fn consume_logical_token(&mut self, value: &str) -> Option<String> {
         let logical_token = self.logical_token().await?;
         if logical_token == value {
@@ -289,55 +342,45 @@ impl<T> Parser<T>
     /*
     * RFC 3986, Page 23
     */
-    async fn consume_path_character(&mut self) -> Result<u8, ErrorType> {
-        match self.consume_unreserved_character().await {
-            Some(character) => return Ok(character),
-            _ => {}
+    async fn consume_path_character(&mut self) -> Result<Option<u8>, ParseError> {
+        if let Some(character) = self.consume_unreserved_character().await {
+            return Ok(Some(character));
         }
-        match self.consume_sub_delim_character().await {
-            Some(character) => return Ok(character),
-            _ => {}
+        if let Some(character) = self.consume_sub_delim_character().await {
+            return Ok(Some(character));
         }
-        match self.consume_char(&COLON).await {
-            Some(character) => return Ok(character),
-            _ => {}
+        if let Some(character) = self.consume_char(&COLON).await {
+            return Ok(Some(character));
         }
-        match self.consume_char(&ATSIGN).await {
-            Some(character) => return Ok(character),
-            _ => {}
+        if let Some(character) = self.consume_char(&ATSIGN).await {
+            return Ok(Some(character));
         }
-        self.consume_percent_encoded().await
+        self.consume_percent_encoded(Rule::Segment).await
     }
 
     /*
     * RFC 3986, Page 50
     */
-    async fn consume_query_character(&mut self) -> Result<u8, ErrorType> {
-        match self.consume_unreserved_character().await {
-            Some(character) => return Ok(character),
-            _ => {}
+    async fn consume_query_character(&mut self) -> Option<u8> {
+        if let Some(character) = self.consume_unreserved_character().await {
+            return Some(character);
         }
-        match self.consume_sub_delim_character().await {
-            Some(character) => return Ok(character),
-            _ => {}
+        if let Some(character) = self.consume_sub_delim_character().await {
+            return Some(character);
         }
-        match self.consume_char(&COLON).await {
-            Some(character) => return Ok(character),
-            _ => {}
+        if let Some(character) = self.consume_char(&COLON).await {
+            return Some(character);
         }
-        match self.consume_char(&ATSIGN).await {
-            Some(character) => return Ok(character),
-            _ => {}
+        if let Some(character) = self.consume_char(&ATSIGN).await {
+            return Some(character);
         }
-        match self.consume_char(&SLASH).await {
-            Some(character) => return Ok(character),
-            _ => {}
+        if let Some(character) = self.consume_char(&SLASH).await {
+            return Some(character);
         }
-        match self.consume_char(&QUESTION_MARK).await {
-            Some(character) => return Ok(character),
-            _ => {}
+        if let Some(character) = self.consume_char(&QUESTION_MARK).await {
+            return Some(character);
         }
-        self.consume_percent_encoded().await
+        None
     }
 
     /*
@@ -350,28 +393,14 @@ impl<T> Parser<T>
     }
 
     async fn consume_optional_whitespace(&mut self) {
-        loop {
-            if let None = self.consume_char(&SPACE).await {
-                if let None = self.consume_char(&HTAB).await {
-                    break;
-                }
-            }
-        }
+        while self.consume_char(&SPACE).await.is_some() || self.consume_char(&HTAB).await.is_some() {}
     }
 
     async fn consume_required_whitespace(&mut self) -> Option<()> {
-        if let None = self.consume_char(&SPACE).await {
-            if let None = self.consume_char(&HTAB).await {
-                return None;
-            }
-        }
-        loop {
-            if let None = self.consume_char(&SPACE).await {
-                if let None = self.consume_char(&HTAB).await {
-                    break;
-                }
-            }
+        if self.consume_char(&SPACE).await.is_none() && self.consume_char(&HTAB).await.is_none() {
+            return None;
         }
+        self.consume_optional_whitespace().await;
         Some(())
     }
 
@@ -381,7 +410,7 @@ impl<T> Parser<T>
     async fn consume_digit(&mut self) -> Option<u8> {
         let next_char = self.source.peek().await?;
         if Self::is_digit_char(next_char) {
-            return self.source.next().await
+            return self.source.next().await;
         }
         None
     }
@@ -389,7 +418,7 @@ impl<T> Parser<T>
     async fn consume_unreserved_character(&mut self) -> Option<u8> {
         let next_char = self.source.peek().await?;
         if Self::is_unreserved_char(next_char) {
-            return self.source.next().await
+            return self.source.next().await;
         }
         None
     }
@@ -397,22 +426,24 @@ impl<T> Parser<T>
     async fn consume_sub_delim_character(&mut self) -> Option<u8> {
         let next_char = self.source.peek().await?;
         if Self::is_sub_delim_char(next_char) {
-            return self.source.next().await
+            return self.source.next().await;
         }
         None
     }
 
-    async fn consume_percent_encoded(&mut self) -> Result<u8, ErrorType> {
-        self.consume_char(&PERCENT).await.ok_or(ErrorType::Missing)?;
-        let high_word = self.consume_hex_digit().await.ok_or(ErrorType::Malformed)?;
-        let low_word = self.consume_hex_digit().await.ok_or(ErrorType::Malformed)?;
-        Self::hex_digits_to_byte(high_word, low_word).ok_or(ErrorType::Malformed)
+    async fn consume_percent_encoded(&mut self, rule: Rule) -> Result<Option<u8>, ParseError> {
+        if self.consume_char(&PERCENT).await.is_none() {
+            return Ok(None);
+        }
+        let high_word = self.consume_hex_digit().await.ok_or_else(|| self.error(rule, ErrorKind::BadTarget))?;
+        let low_word = self.consume_hex_digit().await.ok_or_else(|| self.error(rule, ErrorKind::BadTarget))?;
+        Self::hex_digits_to_byte(high_word, low_word).map(Some).ok_or_else(|| self.error(rule, ErrorKind::BadTarget))
     }
 
     async fn consume_hex_digit(&mut self) -> Option<u8> {
         let next_char = self.source.peek().await?;
         if Self::is_hex_digit_char(next_char) {
-            return self.source.next().await
+            return self.source.next().await;
         }
         None
     }
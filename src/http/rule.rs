@@ -1,20 +1,3 @@
-use std::collections::HashMap;
-
-lazy_static! {
-    static ref METHODS: HashMap<&'static str, Method> = {
-        let mut m = HashMap::new();
-        m.insert("GET", Method::GET);
-        m.insert("HEAD", Method::HEAD);
-        m.insert("POST", Method::POST);
-        m.insert("PUT", Method::PUT);
-        m.insert("DELETE", Method::DELETE);
-        m.insert("CONNECT", Method::CONNECT);
-        m.insert("OPTIONS", Method::OPTIONS);
-        m.insert("TRACE", Method::TRACE);
-        m
-    };
-}
-
 /*
 * RFC 7230, Page 19
 */
@@ -49,15 +32,6 @@ pub struct FieldValue {
     pub content: Vec<u8>,
 }
 
-/*
-* RFC 7230, Page 23
-*/
-#[derive(Debug)]
-pub struct FieldContent {
-    pub first_char: u8,
-    pub second_char: Option<u8>,
-}
-
 /*
 * RFC 7230, Page 21
 */
@@ -70,8 +44,10 @@ pub struct RequestLine {
 
 /*
 * RFC 7231, Page 22
+* Any token is a method; ones we don't know of can still be passed on to a proxied server.
 */
 #[derive(Debug, Clone)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum Method {
     GET,
     HEAD,
@@ -81,11 +57,37 @@ pub enum Method {
     CONNECT,
     OPTIONS,
     TRACE,
+    Other(String),
 }
 
 impl Method {
-    pub fn from_string(string: &str) -> Option<Method> {
-        METHODS.get(string).cloned()
+    // Method names are case-sensitive, so "get" is some other method.
+    pub fn from_string(string: &str) -> Method {
+        match string {
+            "GET" => Method::GET,
+            "HEAD" => Method::HEAD,
+            "POST" => Method::POST,
+            "PUT" => Method::PUT,
+            "DELETE" => Method::DELETE,
+            "CONNECT" => Method::CONNECT,
+            "OPTIONS" => Method::OPTIONS,
+            "TRACE" => Method::TRACE,
+            other => Method::Other(other.to_owned()),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            Method::GET => "GET",
+            Method::HEAD => "HEAD",
+            Method::POST => "POST",
+            Method::PUT => "PUT",
+            Method::DELETE => "DELETE",
+            Method::CONNECT => "CONNECT",
+            Method::OPTIONS => "OPTIONS",
+            Method::TRACE => "TRACE",
+            Method::Other(method) => method,
+        }
     }
 }
 
@@ -120,4 +122,6 @@ pub struct Query {
 #[derive(Debug)]
 pub struct Segment {
     pub lexeme: String,
+    // As sent, still percent-encoded, though with uppercase hex digits.
+    pub raw: String,
 }
@@ -0,0 +1,186 @@
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+use crate::error::Error;
+use crate::policy;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Denial {
+    // Respond with 403 Forbidden.
+    Forbid,
+    // Close the connection without a word.
+    Close,
+}
+
+impl Denial {
+    pub fn from_str(string: &str) -> Option<Denial> {
+        match string {
+            "forbid" => Some(Denial::Forbid),
+            "close" => Some(Denial::Close),
+            _ => None,
+        }
+    }
+}
+
+// An IPv4 or IPv6 address block, like 10.0.0.0/8 or fd00::/8. A bare address is a block of one.
+struct Cidr {
+    address: IpAddr,
+    length: u32,
+}
+
+impl Cidr {
+    fn parse(string: &str) -> Option<Cidr> {
+        let (address, length) = match string.split_once('/') {
+            Some((address, length)) => (address.parse::<IpAddr>().ok()?, Some(length.parse::<u32>().ok()?)),
+            None => (string.parse::<IpAddr>().ok()?, None),
+        };
+        let bits = if address.is_ipv4() { 32 } else { 128 };
+        let length = length.unwrap_or(bits);
+        if length > bits {
+            return None;
+        }
+        // An IPv4-mapped block like ::ffff:10.0.0.0/104 is an IPv4 block, since clients are unmapped.
+        match address.to_canonical() {
+            IpAddr::V4(mapped) if address.is_ipv6() && length >= 96 => Some(Cidr { address: IpAddr::V4(mapped), length: length - 96 }),
+            _ => Some(Cidr { address, length }),
+        }
+    }
+
+    /*
+    * IPv4 clients can reach us over IPv6 as ::ffff:a.b.c.d, and the same client might come over
+    * either, so IPv4 clients match IPv4 blocks as themselves and IPv6 blocks in mapped form.
+    */
+    fn contains(&self, address: IpAddr) -> bool {
+        let (block, address, bits) = match (self.address, address.to_canonical()) {
+            (IpAddr::V4(block), IpAddr::V4(address)) => (u128::from(u32::from(block)), u128::from(u32::from(address)), 32),
+            (IpAddr::V4(_), IpAddr::V6(_)) => return false,
+            (IpAddr::V6(block), IpAddr::V4(address)) => (u128::from(block), u128::from(address.to_ipv6_mapped()), 128),
+            (IpAddr::V6(block), IpAddr::V6(address)) => (u128::from(block), u128::from(address), 128),
+        };
+        let shift = bits - self.length;
+        // Shifting by the full width would overflow; a /0 matches everything anyway.
+        shift >= bits || block >> shift == address >> shift
+    }
+}
+
+struct Rule {
+    // Relative to the root, or None for rules that apply everywhere.
+    scope: Option<PathBuf>,
+    allow: bool,
+    block: Cidr,
+}
+
+/*
+* Which clients may talk to us, by address. Rules apply everywhere or under a path prefix. Within
+* each scope, a client is refused if it matches a deny rule, or if there are allow rules and it
+* matches none of them. A client has to get through every scope covering the path.
+*/
+pub struct IpFilter {
+    rules: Vec<Rule>,
+    pub denial: Denial,
+}
+
+impl Default for IpFilter {
+    fn default() -> Self {
+        Self {
+            rules: Vec::new(),
+            denial: Denial::Forbid,
+        }
+    }
+}
+
+impl IpFilter {
+    // Add a rule given as CIDR or PREFIX=CIDR.
+    pub fn add(&mut self, spec: &str, allow: bool) -> Result<(), String> {
+        let (scope, block) = match spec.split_once('=') {
+            Some((prefix, block)) => {
+                let segments: Vec<String> = prefix.split('/').map(str::to_owned).collect();
+                (Some(policy::normalize(&segments).map_err(|_| format!("{} leads outside the root", prefix))?), block)
+            },
+            None => (None, spec),
+        };
+        let block = Cidr::parse(block).ok_or_else(|| format!("{} is not an address or CIDR block", block))?;
+        self.rules.push(Rule { scope, allow, block });
+        Ok(())
+    }
+
+    // The error for a refused client.
+    pub fn refusal(&self) -> Error {
+        match self.denial {
+            Denial::Forbid => Error::Forbidden,
+            Denial::Close => Error::Refused,
+        }
+    }
+
+    // Whether `address` gets through the rules that apply everywhere.
+    pub fn permits_peer(&self, address: IpAddr) -> bool {
+        self.permits_scope(address, &None)
+    }
+
+    // Whether `address` gets through the rules for prefixes covering `path`, relative to the root.
+    pub fn permits_path(&self, address: IpAddr, path: &Path) -> bool {
+        let mut scopes: Vec<&Option<PathBuf>> = Vec::new();
+        for rule in &self.rules {
+            if rule.scope.as_ref().is_some_and(|scope| path.starts_with(scope)) && !scopes.contains(&&rule.scope) {
+                scopes.push(&rule.scope);
+            }
+        }
+        scopes.into_iter().all(|scope| self.permits_scope(address, scope))
+    }
+
+    fn permits_scope(&self, address: IpAddr, scope: &Option<PathBuf>) -> bool {
+        let mut allowed = None;
+        for rule in self.rules.iter().filter(|rule| &rule.scope == scope) {
+            if rule.block.contains(address) {
+                if !rule.allow {
+                    return false;
+                }
+                allowed = Some(true);
+            } else if rule.allow && allowed.is_none() {
+                allowed = Some(false);
+            }
+        }
+        allowed.unwrap_or(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contains(block: &str, address: &str) -> bool {
+        Cidr::parse(block).unwrap().contains(address.parse().unwrap())
+    }
+
+    #[test]
+    fn ipv4_blocks_match_plain_and_mapped_clients() {
+        assert!(contains("10.0.0.0/8", "10.1.2.3"));
+        assert!(contains("10.0.0.0/8", "::ffff:10.1.2.3"));
+        assert!(!contains("10.0.0.0/8", "11.0.0.1"));
+        assert!(!contains("10.0.0.0/8", "::ffff:11.0.0.1"));
+    }
+
+    #[test]
+    fn mapped_blocks_are_ipv4_blocks() {
+        assert!(contains("::ffff:10.0.0.0/104", "10.1.2.3"));
+        assert!(contains("::ffff:10.0.0.0/104", "::ffff:10.1.2.3"));
+        assert!(!contains("::ffff:10.0.0.0/104", "11.0.0.1"));
+    }
+
+    #[test]
+    fn short_ipv6_blocks_match_ipv4_clients_in_mapped_form() {
+        assert!(contains("::/0", "192.168.1.1"));
+        assert!(contains("::/0", "::ffff:192.168.1.1"));
+        assert!(contains("::ffff:0:0/80", "::ffff:192.168.1.1"));
+        assert!(contains("::ffff:0:0/80", "192.168.1.1"));
+        assert!(!contains("fd00::/8", "::ffff:192.168.1.1"));
+    }
+
+    #[test]
+    fn ipv6_blocks_match_ipv6_clients() {
+        assert!(contains("fd00::/8", "fd12::1"));
+        assert!(!contains("fd00::/8", "fe80::1"));
+        assert!(contains("::1", "::1"));
+        assert!(!contains("10.0.0.0/8", "fd00::1"));
+    }
+}
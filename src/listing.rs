@@ -0,0 +1,471 @@
+use std::cmp::Ordering;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use async_std::fs;
+use async_std::prelude::*;
+
+use chrono::offset::Local;
+use chrono::DateTime;
+
+use crate::error::Result;
+use crate::http::{HTTPRequest, percent_encode};
+use crate::html;
+use crate::template;
+use crate::policy::Rules;
+
+pub struct Entry {
+    pub name: String,
+    pub href: String,
+    pub is_dir: bool,
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+    // Where the entry points, if it's a symbolic link.
+    pub link_target: Option<String>,
+}
+
+impl Entry {
+    fn type_name(&self) -> &'static str {
+        if self.is_dir {
+            "directory"
+        } else {
+            "file"
+        }
+    }
+
+    fn extension(&self) -> Option<String> {
+        Path::new(&self.name).extension().and_then(|extension| extension.to_str()).map(|extension| extension.to_lowercase())
+    }
+}
+
+// The entries of the directory at `path`, which is `relative` to the root, that `rules` permit.
+pub async fn read_entries(path: &Path, relative: &Path, rules: &Rules<'_>) -> Result<Vec<Entry>> {
+    let mut listing = Vec::new();
+    let mut entries = fs::read_dir(path).await?;
+    while let Some(entry) = entries.next().await {
+        let entry = entry?;
+        let file_path: PathBuf = entry.path().into();
+        // Entries can disappear between read_dir() and metadata(), and names that aren't
+        // UTF-8 can't be requested anyway; neither is worth failing the whole listing over.
+        let metadata = match fs::metadata(&file_path).await {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+        let name = match file_path.file_name().and_then(|name| name.to_str()) {
+            Some(name) => name.to_owned(),
+            None => continue,
+        };
+        if !rules.permits(&relative.join(&name), metadata.is_dir()) {
+            continue;
+        }
+        let link_target = match entry.file_type().await {
+            Ok(file_type) if file_type.is_symlink() => {
+                // A visible link to a hidden file would only lead to a 404.
+                match fs::canonicalize(&file_path).await {
+                    Ok(target) if rules.permits_target(target.as_ref(), metadata.is_dir()).await => {},
+                    _ => continue,
+                }
+                fs::read_link(&file_path).await.ok().and_then(|target| target.to_str().map(str::to_owned))
+            },
+            _ => None,
+        };
+        // Listings are only ever served from URLs ending in a slash, so relative links resolve
+        // against the directory itself. Linking directories with their slash saves a redirect.
+        let href = if metadata.is_dir() {
+            format!("{}/", percent_encode(&name))
+        } else {
+            percent_encode(&name)
+        };
+        listing.push(Entry {
+            name,
+            href,
+            is_dir: metadata.is_dir(),
+            size: metadata.len(),
+            modified: metadata.modified().ok(),
+            link_target,
+        });
+    }
+    Ok(listing)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum SortKey {
+    Name,
+    Size,
+    Modified,
+    Type,
+}
+
+impl SortKey {
+    fn from_str(string: &str) -> Option<SortKey> {
+        match string {
+            "name" => Some(SortKey::Name),
+            "size" => Some(SortKey::Size),
+            "mtime" => Some(SortKey::Modified),
+            "type" => Some(SortKey::Type),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            SortKey::Name => "name",
+            SortKey::Size => "size",
+            SortKey::Modified => "mtime",
+            SortKey::Type => "type",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum SortOrder {
+    Ascending,
+    Descending,
+}
+
+impl SortOrder {
+    fn from_str(string: &str) -> Option<SortOrder> {
+        match string {
+            "asc" => Some(SortOrder::Ascending),
+            "desc" => Some(SortOrder::Descending),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            SortOrder::Ascending => "asc",
+            SortOrder::Descending => "desc",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Format {
+    Html,
+    Json,
+    Text,
+}
+
+impl Format {
+    /*
+    * Selected with ?format=html|json|text, or failing that, by the Accept header. Browsers ask
+    * for text/html first, and so does anyone who doesn't care.
+    */
+    pub fn from_request(request: &HTTPRequest) -> Self {
+        match request.query_parameter("format").as_deref() {
+            Some("html") => return Format::Html,
+            Some("json") => return Format::Json,
+            Some("text") => return Format::Text,
+            _ => {},
+        }
+        let mut best = (Format::Html, 0.0);
+        for format in &[Format::Html, Format::Json, Format::Text] {
+            let quality = request.accept_quality(format.media_type());
+            if quality > best.1 {
+                best = (*format, quality);
+            }
+        }
+        best.0
+    }
+
+    fn media_type(&self) -> &'static str {
+        match self {
+            Format::Html => "text/html",
+            Format::Json => "application/json",
+            Format::Text => "text/plain",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::Html => "text/html; charset=utf-8",
+            Format::Json => "application/json",
+            Format::Text => "text/plain; charset=utf-8",
+        }
+    }
+
+    pub fn render(&self, friendly_name: &str, has_parent: bool, entries: &[Entry], sort: &Sort, templates: &Templates) -> String {
+        match self {
+            Format::Html => render_html(friendly_name, has_parent, entries, sort, templates),
+            Format::Json => render_json(entries),
+            Format::Text => render_text(entries),
+        }
+    }
+}
+
+/*
+* Selected with ?sort=name|size|mtime|type&order=asc|desc&dirs=first|mixed. Anything we don't
+* understand falls back to the default, which is by name, ascending, directories first.
+*/
+pub struct Sort {
+    pub key: SortKey,
+    pub order: SortOrder,
+    pub directories_first: bool,
+}
+
+impl Sort {
+    pub fn from_request(request: &HTTPRequest) -> Self {
+        Self {
+            key: request.query_parameter("sort").and_then(|key| SortKey::from_str(&key)).unwrap_or(SortKey::Name),
+            order: request.query_parameter("order").and_then(|order| SortOrder::from_str(&order)).unwrap_or(SortOrder::Ascending),
+            directories_first: request.query_parameter("dirs").as_deref() != Some("mixed"),
+        }
+    }
+
+    pub fn apply(&self, entries: &mut [Entry]) {
+        entries.sort_by(|a, b| {
+            let ordering = match self.key {
+                SortKey::Name => Ordering::Equal,
+                SortKey::Size => a.size.cmp(&b.size),
+                SortKey::Modified => a.modified.cmp(&b.modified),
+                SortKey::Type => a.extension().cmp(&b.extension()),
+            }.then_with(|| natural_cmp(&a.name, &b.name));
+            let ordering = match self.order {
+                SortOrder::Ascending => ordering,
+                SortOrder::Descending => ordering.reverse(),
+            };
+            if self.directories_first {
+                b.is_dir.cmp(&a.is_dir).then(ordering)
+            } else {
+                ordering
+            }
+        });
+    }
+
+    // The query string a column header should link to: the opposite order if the listing is
+    // already sorted by that column, ascending otherwise.
+    fn query_for(&self, key: SortKey) -> String {
+        let order = if self.key == key && self.order == SortOrder::Ascending {
+            SortOrder::Descending
+        } else {
+            SortOrder::Ascending
+        };
+        let mut query = format!("sort={}&order={}", key.as_str(), order.as_str());
+        if !self.directories_first {
+            query.push_str("&dirs=mixed");
+        }
+        query
+    }
+}
+
+/*
+* Compare names the way a person would: runs of digits compare by their numeric value, so that
+* "file2" sorts before "file10", and letters compare case-insensitively.
+*/
+fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let mut a_chars = a.chars().peekable();
+    let mut b_chars = b.chars().peekable();
+    loop {
+        match (a_chars.peek(), b_chars.peek()) {
+            (None, None) => return a.cmp(b),
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(a_char), Some(b_char)) if a_char.is_ascii_digit() && b_char.is_ascii_digit() => {
+                let mut a_digits = String::new();
+                while let Some(digit) = a_chars.next_if(|character| character.is_ascii_digit()) {
+                    a_digits.push(digit);
+                }
+                let mut b_digits = String::new();
+                while let Some(digit) = b_chars.next_if(|character| character.is_ascii_digit()) {
+                    b_digits.push(digit);
+                }
+                let a_number = a_digits.trim_start_matches('0');
+                let b_number = b_digits.trim_start_matches('0');
+                let ordering = a_number.len().cmp(&b_number.len()).then_with(|| a_number.cmp(b_number));
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+            },
+            (Some(a_char), Some(b_char)) => {
+                let ordering = a_char.to_lowercase().cmp(b_char.to_lowercase());
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+                a_chars.next();
+                b_chars.next();
+            },
+        }
+    }
+}
+
+const LISTING_PLACEHOLDERS: &[&str] = &["path", "breadcrumbs", "downloads", "entries", "sort_name", "sort_mtime", "sort_size", "sort_type"];
+// The directory as an archive; see archive.rs.
+const DOWNLOADS: &str = "<a href=\"?download=zip\">zip</a> <a href=\"?download=tar.gz\">tar.gz</a>";
+const ENTRY_PLACEHOLDERS: &[&str] = &["name", "href", "size", "mtime", "type", "extension", "link"];
+
+/*
+* The HTML listing is listing.html, with one listing_entry.html per entry in place of {entries}.
+* Either can be replaced by a file of the same name in the template directory.
+*/
+pub struct Templates {
+    pub listing: String,
+    pub entry: String,
+}
+
+impl Default for Templates {
+    fn default() -> Self {
+        Self {
+            listing: include_str!("../res/listing.html").to_owned(),
+            entry: include_str!("../res/listing_entry.html").to_owned(),
+        }
+    }
+}
+
+impl Templates {
+    pub fn load(directory: &Path) -> std::result::Result<Self, String> {
+        let mut templates = Self::default();
+        if let Some(listing) = Self::load_one(directory, "listing.html", LISTING_PLACEHOLDERS)? {
+            if !template::placeholders(&listing).contains(&"entries") {
+                return Err(format!("{}: listing.html has no {{entries}} placeholder", directory.display()));
+            }
+            templates.listing = listing;
+        }
+        if let Some(entry) = Self::load_one(directory, "listing_entry.html", ENTRY_PLACEHOLDERS)? {
+            templates.entry = entry;
+        }
+        Ok(templates)
+    }
+
+    fn load_one(directory: &Path, name: &str, known: &[&str]) -> std::result::Result<Option<String>, String> {
+        let path = directory.join(name);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let template = std::fs::read_to_string(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        for placeholder in template::placeholders(&template) {
+            if !known.contains(&placeholder) {
+                // Could just as well be a script or stylesheet, so it stays in as it is.
+                eprintln!("Warning: {}: unknown placeholder {{{}}} (expected one of {})", path.display(), placeholder, known.join(", "));
+            }
+        }
+        Ok(Some(template))
+    }
+}
+
+fn render_html(friendly_name: &str, has_parent: bool, entries: &[Entry], sort: &Sort, templates: &Templates) -> String {
+    let mut listings = Vec::new();
+    if has_parent {
+        listings.push(template::fill(&templates.entry, &[
+            ("name", ".."),
+            ("href", "../"),
+            ("size", "-"),
+            ("mtime", "-"),
+            ("type", "directory"),
+            ("extension", "-"),
+            ("link", ""),
+        ]));
+    }
+    for entry in entries {
+        let modified_time = match entry.modified {
+            Some(modified) => {
+                let formatted_time: DateTime<Local> = modified.into();
+                formatted_time.format("%d-%b-%Y %H:%M").to_string()
+            },
+            None => "-".to_owned(),
+        };
+        let size = if entry.is_dir {
+            "-".to_owned()
+        } else {
+            entry.size.to_string()
+        };
+        listings.push(template::fill(&templates.entry, &[
+            ("name", &html::escape(&entry.name)),
+            ("href", &entry.href),
+            ("size", &size),
+            ("mtime", &modified_time),
+            ("type", entry.type_name()),
+            // What ?sort=type sorts files by.
+            ("extension", &match entry.extension() {
+                Some(extension) if !entry.is_dir => html::escape(&extension),
+                _ => "-".to_owned(),
+            }),
+            ("link", &match &entry.link_target {
+                Some(link_target) => format!(" &rarr; {}", html::escape(link_target)),
+                None => String::new(),
+            }),
+        ]));
+    }
+    let path = if friendly_name.is_empty() {
+        "/".to_owned()
+    } else {
+        format!("/{}/", friendly_name)
+    };
+    template::fill(&templates.listing, &[
+        ("path", &html::escape(&path)),
+        ("breadcrumbs", &breadcrumbs(friendly_name)),
+        ("downloads", DOWNLOADS),
+        ("entries", &listings.join("\n")),
+        ("sort_name", &html::escape(&format!("?{}", sort.query_for(SortKey::Name)))),
+        ("sort_mtime", &html::escape(&format!("?{}", sort.query_for(SortKey::Modified)))),
+        ("sort_size", &html::escape(&format!("?{}", sort.query_for(SortKey::Size)))),
+        ("sort_type", &html::escape(&format!("?{}", sort.query_for(SortKey::Type)))),
+    ])
+}
+
+// Links to the root and each directory on the way to this one, relative to this one.
+fn breadcrumbs(friendly_name: &str) -> String {
+    let names: Vec<&str> = friendly_name.split('/').filter(|name| !name.is_empty()).collect();
+    let href = |up: usize| if up == 0 { "./".to_owned() } else { "../".repeat(up) };
+    let mut breadcrumbs = format!("<a href=\"{}\">/</a>", href(names.len()));
+    for (depth, name) in names.iter().enumerate() {
+        breadcrumbs.push_str(&format!("<a href=\"{}\">{}</a>/", href(names.len() - depth - 1), html::escape(name)));
+    }
+    breadcrumbs
+}
+
+fn render_json(entries: &[Entry]) -> String {
+    let objects: Vec<String> = entries.iter().map(|entry| {
+        format!(
+            "  {{\"name\": {}, \"type\": \"{}\", \"size\": {}, \"mtime\": {}, \"link_target\": {}}}",
+            json_string(&entry.name),
+            entry.type_name(),
+            if entry.is_dir { "null".to_owned() } else { entry.size.to_string() },
+            match entry.modified.and_then(|modified| modified.duration_since(UNIX_EPOCH).ok()) {
+                Some(modified) => modified.as_secs().to_string(),
+                None => "null".to_owned(),
+            },
+            match &entry.link_target {
+                Some(link_target) => json_string(link_target),
+                None => "null".to_owned(),
+            },
+        )
+    }).collect();
+    if objects.is_empty() {
+        "[]\n".to_owned()
+    } else {
+        format!("[\n{}\n]\n", objects.join(",\n"))
+    }
+}
+
+// One name per line, with a trailing slash on directories, like `ls -p`.
+fn render_text(entries: &[Entry]) -> String {
+    entries.iter().map(|entry| {
+        if entry.is_dir {
+            format!("{}/\n", entry.name)
+        } else {
+            format!("{}\n", entry.name)
+        }
+    }).collect()
+}
+
+/*
+* RFC 8259, Page 8
+*/
+fn json_string(string: &str) -> String {
+    let mut quoted = String::with_capacity(string.len() + 2);
+    quoted.push('"');
+    for character in string.chars() {
+        match character {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            character if (character as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", character as u32)),
+            character => quoted.push(character),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
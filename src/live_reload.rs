@@ -0,0 +1,305 @@
+use std::collections::HashMap;
+use std::env;
+use std::ffi::{CString, OsStr};
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use async_std::channel::{self, Receiver, Sender};
+use async_std::task;
+use futures::future::{self, BoxFuture, Either};
+use futures::stream::{self, StreamExt};
+
+use crate::config::Config;
+use crate::http::percent_encode;
+use crate::response::{self, ByteStream};
+use crate::websocket::{Message, Received, WebSocket, CLOSE_GOING_AWAY};
+
+// Under a name no site is likely to use.
+pub const EVENTS_PATH: &str = "/__tiny-serve/live-reload";
+pub const SCRIPT_PATH: &str = "/__tiny-serve/live-reload.js";
+
+const SCRIPT: &[u8] = include_bytes!("../res/live-reload.js");
+// Appended to HTML pages. Browsers put anything after the end of the document into the body.
+const SNIPPET: &[u8] = b"\n<script src=\"/__tiny-serve/live-reload.js\"></script>\n";
+
+// How long things have to stay quiet before changes are announced, so one save is one reload.
+const SETTLE_MILLISECONDS: i32 = 100;
+const WATCH_MASK: u32 = libc::IN_CLOSE_WRITE | libc::IN_CREATE | libc::IN_DELETE | libc::IN_MOVED_FROM | libc::IN_MOVED_TO;
+
+#[derive(Clone)]
+enum Change {
+    Reload,
+    // Only these stylesheets, as URL paths, changed.
+    Stylesheets(Vec<String>),
+}
+
+impl Change {
+    // As a WebSocket message: {"type":"reload"}, or {"type":"css","paths":[...]}.
+    fn message(&self) -> Message {
+        Message::Text(match self {
+            Change::Reload => r#"{"type":"reload"}"#.to_owned(),
+            // Percent-encoded, so there's nothing to escape.
+            Change::Stylesheets(paths) => {
+                let paths: Vec<String> = paths.iter().map(|path| format!("\"{}\"", path)).collect();
+                format!(r#"{{"type":"css","paths":[{}]}}"#, paths.join(","))
+            },
+        })
+    }
+
+    // As a server-sent event (HTML Living Standard, 9.2).
+    fn event(&self) -> Vec<u8> {
+        match self {
+            Change::Reload => b"event: reload\ndata:\n\n".to_vec(),
+            Change::Stylesheets(paths) => {
+                let data: String = paths.iter().map(|path| format!("data: {}\n", path)).collect();
+                format!("event: css\n{}\n", data).into_bytes()
+            },
+        }
+    }
+}
+
+struct Subscriber {
+    root: PathBuf,
+    sender: Sender<Change>,
+}
+
+/*
+* Reloads pages in the browser when the files under their root change. HTML pages get a script
+* that listens for server-sent events, which a thread watching the roots with inotify pushes. When
+* only stylesheets changed, they're swapped in place rather than reloading the page.
+*/
+#[derive(Default)]
+pub struct LiveReload {
+    pub enabled: bool,
+    subscribers: Mutex<Vec<Subscriber>>,
+}
+
+impl LiveReload {
+    // The script pages load to listen for changes.
+    pub fn script(&self) -> response::Ok {
+        response::Ok {
+            headers: vec![
+                ("Content-Type".to_owned(), "text/javascript; charset=utf-8".to_owned()),
+                ("Cache-Control".to_owned(), "no-cache".to_owned()),
+            ],
+            file_stream: Box::new(stream::iter(vec![Ok(SCRIPT.to_vec())])),
+            generated: false,
+        }
+    }
+
+    fn subscribe(&self, root: &Path) -> Receiver<Change> {
+        let (sender, receiver) = channel::unbounded();
+        self.subscribers.lock().unwrap().push(Subscriber { root: root.to_path_buf(), sender });
+        receiver
+    }
+
+    // An endless stream of events for changes under `root`.
+    pub fn events(&self, root: &Path) -> response::Ok {
+        let receiver = self.subscribe(root);
+        // Browsers reconnect on their own, but by default only after a few seconds.
+        let retry = stream::iter(vec![Ok(b"retry: 1000\n\n".to_vec())]);
+        response::Ok {
+            headers: vec![
+                ("Content-Type".to_owned(), "text/event-stream".to_owned()),
+                ("Cache-Control".to_owned(), "no-cache".to_owned()),
+            ],
+            file_stream: Box::new(retry.chain(receiver.map(|change: Change| Ok(change.event())))),
+            generated: false,
+        }
+    }
+
+    // `page`, an HTML file, with the script added.
+    pub fn inject(&self, page: ByteStream) -> ByteStream {
+        Box::new(page.chain(stream::iter(vec![Ok(SNIPPET.to_vec())])))
+    }
+
+    // Tell the pages under `root` about a change, and forget those that have gone away.
+    fn publish(&self, root: &Path, change: Change) {
+        self.subscribers.lock().unwrap().retain(|subscriber| {
+            subscriber.root != root || subscriber.sender.try_send(change.clone()).is_ok()
+        });
+    }
+}
+
+// Push changes under `root` over a WebSocket, until the client goes away or the server does.
+pub fn changes(mut socket: WebSocket, config: Arc<Config>, root: PathBuf) -> BoxFuture<'static, io::Result<()>> {
+    Box::pin(async move {
+        let receiver = config.live_reload.subscribe(&root);
+        loop {
+            // Whichever comes first; the other is dropped, which loses nothing.
+            let next = match future::select(Box::pin(receiver.recv()), Box::pin(socket.readable())).await {
+                Either::Left((change, _)) => Either::Left(change),
+                Either::Right((readable, _)) => Either::Right(readable),
+            };
+            match next {
+                Either::Left(Ok(change)) => socket.send(&change.message()).await?,
+                Either::Left(Err(_)) => return socket.close(CLOSE_GOING_AWAY).await,
+                // Nothing the client says matters, but pings need answering and closes closing.
+                Either::Right(true) => if let Received::Closed = socket.receive_frame().await? {
+                    return Ok(());
+                },
+                Either::Right(false) => return Ok(()),
+            }
+        }
+    })
+}
+
+// Start watching the current directory and every virtual host's root, for as long as we run.
+pub fn watch(config: Arc<Config>) {
+    let mut roots: Vec<PathBuf> = config.hosts.roots().map(Path::to_path_buf).collect();
+    match env::current_dir().and_then(|current_dir| current_dir.canonicalize()) {
+        Ok(current_dir) => roots.push(current_dir),
+        Err(e) => eprintln!("--live-reload: {}", e),
+    }
+    thread::spawn(move || {
+        if let Err(e) = Watcher::new(config, roots).and_then(|mut watcher| watcher.run()) {
+            eprintln!("--live-reload: {}", e);
+        }
+    });
+}
+
+struct Watcher {
+    config: Arc<Config>,
+    roots: Vec<PathBuf>,
+    fd: i32,
+    // Watch descriptors to the directories they watch.
+    directories: HashMap<i32, PathBuf>,
+    reported_limit: bool,
+}
+
+impl Watcher {
+    fn new(config: Arc<Config>, roots: Vec<PathBuf>) -> io::Result<Self> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut watcher = Self { config, roots, fd, directories: HashMap::new(), reported_limit: false };
+        for root in watcher.roots.clone() {
+            watcher.watch_tree(&root);
+        }
+        Ok(watcher)
+    }
+
+    fn run(&mut self) -> io::Result<()> {
+        let mut buffer = vec![0u8; 64 * 1024];
+        loop {
+            let mut changed = Vec::new();
+            let mut overflowed = false;
+            // Block until something happens, then gather up whatever follows close behind.
+            let mut timeout = -1;
+            loop {
+                let mut poll = libc::pollfd { fd: self.fd, events: libc::POLLIN, revents: 0 };
+                match unsafe { libc::poll(&mut poll, 1, timeout) } {
+                    0 => break,
+                    ready if ready < 0 => {
+                        let error = io::Error::last_os_error();
+                        if error.kind() == io::ErrorKind::Interrupted {
+                            continue;
+                        }
+                        return Err(error);
+                    },
+                    _ => {},
+                }
+                let size = unsafe { libc::read(self.fd, buffer.as_mut_ptr() as *mut libc::c_void, buffer.len()) };
+                if size < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                overflowed |= self.read_events(&buffer[..size as usize], &mut changed);
+                timeout = SETTLE_MILLISECONDS;
+            }
+            for root in &self.roots {
+                if let Some(change) = self.change(root, &changed, overflowed) {
+                    self.config.live_reload.publish(root, change);
+                }
+            }
+        }
+    }
+
+    // Add the paths `events` are about to `changed`. True if the kernel dropped events.
+    fn read_events(&mut self, mut events: &[u8], changed: &mut Vec<PathBuf>) -> bool {
+        let mut overflowed = false;
+        while events.len() >= mem::size_of::<libc::inotify_event>() {
+            let event = unsafe { (events.as_ptr() as *const libc::inotify_event).read_unaligned() };
+            let end = mem::size_of::<libc::inotify_event>() + event.len as usize;
+            // The name is padded with NULs.
+            let name = events[mem::size_of::<libc::inotify_event>()..end].split(|byte| *byte == 0).next().unwrap_or_default();
+            events = &events[end..];
+            if event.mask & libc::IN_Q_OVERFLOW != 0 {
+                overflowed = true;
+                continue;
+            }
+            if event.mask & libc::IN_IGNORED != 0 {
+                self.directories.remove(&event.wd);
+                continue;
+            }
+            let path = match self.directories.get(&event.wd) {
+                Some(directory) => directory.join(OsStr::from_bytes(name)),
+                None => continue,
+            };
+            // A new directory's contents have to be watched too.
+            if event.mask & libc::IN_ISDIR != 0 && event.mask & (libc::IN_CREATE | libc::IN_MOVED_TO) != 0 {
+                self.watch_tree(&path);
+            }
+            changed.push(path);
+        }
+        overflowed
+    }
+
+    // What pages under `root` should do about `changed`, if anything.
+    fn change(&self, root: &Path, changed: &[PathBuf], overflowed: bool) -> Option<Change> {
+        if overflowed {
+            return Some(Change::Reload);
+        }
+        // Hidden files, like an editor's swap files or .git, are nobody's business.
+        let visible: Vec<&Path> = changed.iter()
+            .filter_map(|path| path.strip_prefix(root).ok())
+            .filter(|relative| task::block_on(self.config.policy.permits(root, relative, root.join(relative).is_dir())))
+            .collect();
+        if visible.is_empty() {
+            return None;
+        }
+        if !visible.iter().all(|relative| relative.extension().is_some_and(|extension| extension == "css")) {
+            return Some(Change::Reload);
+        }
+        Some(Change::Stylesheets(visible.iter().map(|relative| {
+            relative.iter().map(|segment| format!("/{}", percent_encode(&segment.to_string_lossy()))).collect()
+        }).collect()))
+    }
+
+    // Watch `directory` and the visible directories below it.
+    fn watch_tree(&mut self, directory: &Path) {
+        let path = match CString::new(directory.as_os_str().as_bytes()) {
+            Ok(path) => path,
+            Err(_) => return,
+        };
+        let wd = unsafe { libc::inotify_add_watch(self.fd, path.as_ptr(), WATCH_MASK | libc::IN_ONLYDIR) };
+        if wd < 0 {
+            // Most likely fs.inotify.max_user_watches; saying so once is enough.
+            if !self.reported_limit {
+                eprintln!("--live-reload: can't watch {}: {}", directory.display(), io::Error::last_os_error());
+                self.reported_limit = true;
+            }
+            return;
+        }
+        self.directories.insert(wd, directory.to_path_buf());
+        let entries = match std::fs::read_dir(directory) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        for entry in entries.flatten() {
+            // Symbolic links aren't followed, so each directory is only watched once.
+            if !entry.file_type().is_ok_and(|file_type| file_type.is_dir()) {
+                continue;
+            }
+            let path = entry.path();
+            let root = self.roots.iter().filter(|root| path.starts_with(root)).max_by_key(|root| root.components().count());
+            if root.is_some_and(|root| task::block_on(self.config.policy.permits(root, path.strip_prefix(root).unwrap_or(&path), true))) {
+                self.watch_tree(&path);
+            }
+        }
+    }
+}
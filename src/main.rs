@@ -1,17 +1,41 @@
-#[macro_use]
-extern crate lazy_static;
-
 mod http;
 mod peekable_bufreader;
 mod response;
 mod chunked_bufreader;
+mod error;
+mod listing;
+mod config;
+mod html;
+mod policy;
+mod template;
+mod auth;
+mod access_log;
+mod tokens;
+mod signing;
+mod ip_filter;
+mod rate_limit;
+mod cors;
+mod security;
+mod mime;
+mod custom_headers;
+mod watched_file;
+mod redirects;
+mod vhosts;
+mod proxy;
+mod live_reload;
+mod websocket;
+mod archive;
 
 use std::env;
-use std::path::PathBuf;
-use std::path::Path;
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
 
 use futures::stream;
 use futures::stream::StreamExt;
+use futures::FutureExt;
 
 use async_std::prelude::*;
 use async_std::task;
@@ -20,140 +44,384 @@ use async_std::io::{BufReader, BufWriter};
 use async_std::fs::File;
 use async_std::fs;
 
-use chrono::offset::Local;
-use chrono::DateTime;
-
-use http::{Parser, Method};
+use http::{Parser, HTTPRequest, Method, percent_encode};
 use peekable_bufreader::PeekableBufReader;
 use chunked_bufreader::ChunkedBufReader;
-use response::{Response, BadRequest, NotFound, NotImplemented, InternalServerError};
+use error::{Error, Result};
+use listing::{Sort, Format};
+use config::Config;
+use redirects::Outcome;
+use rate_limit::LimitBy;
+use response::{Response, ByteStream, Redirect, RedirectKind, ErrorPage, Status};
+
+// What's left of the connection after the request's header section.
+type RequestBody = PeekableBufReader<TcpStream>;
 
 #[async_std::main]
 async fn main() {
-    let port = env::args().skip(1).next().unwrap_or("8000".to_owned()).parse::<u16>().unwrap_or(8000);
-    let listener = TcpListener::bind(format!("0.0.0.0:{}", port)).await;
-    match listener {
+    if env::args().nth(1).as_deref() == Some("sign") {
+        if env::args().skip(2).any(|arg| arg == "-h" || arg == "--help") {
+            print!("{}", signing::USAGE);
+            return;
+        }
+        if let Err(e) = signing::run(env::args().skip(2)) {
+            eprintln!("{}\n\n{}", e, signing::USAGE);
+            process::exit(2);
+        }
+        return;
+    }
+    if env::args().skip(1).any(|arg| arg == "-h" || arg == "--help") {
+        print!("{}", config::USAGE);
+        return;
+    }
+    let config = match Config::from_args(env::args().skip(1)) {
+        Ok(config) => Arc::new(config),
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, config::USAGE);
+            process::exit(2);
+        },
+    };
+    if config.live_reload.enabled {
+        live_reload::watch(config.clone());
+    }
+    let listener = match TcpListener::bind(format!("0.0.0.0:{}", config.port)).await {
+        Ok(listener) => listener,
         Err(e) => {
             eprintln!("Failed to bind TCP Listener: {}", e);
             return;
         },
-        _ => {},
-    }
-    let listener = listener.unwrap();
+    };
     listener
         .incoming()
-        .for_each_concurrent(None, |stream| async move {
-            if let Ok(valid) = stream {
-                task::spawn(handle_connection(valid));
+        .for_each_concurrent(None, |stream| {
+            let config = config.clone();
+            async move {
+                if let Ok(valid) = stream {
+                    task::spawn(handle_connection(valid, config));
+                }
             }
         })
         .await;
 }
 
-async fn handle_connection(mut stream: TcpStream) {
-    let response = generate_response(&stream).await.response_bytes();
-    let writer = BufWriter::new(&mut stream);
-    let mut writer = response.fold(writer, |mut writer, bytes| async move {
-        writer.write(&bytes).await.unwrap();
-        writer
-    }).await;
-    writer.flush().await.unwrap();
-    stream.flush().await.unwrap();
-}
-
-async fn generate_response(stream: &TcpStream) -> Box<dyn Response> {
-    let reader = PeekableBufReader::new(BufReader::new(stream));
-    let request = match Parser::new(reader).parse().await {
-        Some(success) => success,
-        None => return Box::new(BadRequest{}),
+async fn handle_connection(mut stream: TcpStream, config: Arc<Config>) {
+    // Without a peer address, the client is already gone.
+    let peer = match stream.peer_addr() {
+        Ok(peer) => peer,
+        Err(_) => return,
     };
-    match request.method {
-        Method::GET => {
-            if request.requested_path.iter().filter(|segment| segment.contains("/")).count() != 0 {
-                return Box::new(BadRequest{});
-            } else {
-                let path = match PathBuf::from("./".to_owned() + &request.requested_path.join("/")).canonicalize() {
-                    Ok(canonical_path) => canonical_path,
-                    Err(_) => return Box::new(NotFound{}),
+    let mut entry = access_log::Entry::new(peer);
+    let mut request = None;
+    let mut root = None;
+    let mut body = None;
+    let result = if !config.ip_filter.permits_peer(peer.ip()) {
+        // Refused before reading a byte of the request.
+        Err(config.ip_filter.refusal())
+    } else {
+        // Its own handle on the stream, so that it can outlive this request for an upgrade.
+        let mut parser = Parser::new(PeekableBufReader::new(BufReader::new(stream.clone())));
+        match parser.parse().await {
+            Ok(parsed) => {
+                entry.request(&parsed);
+                let mut reader = parser.into_inner();
+                // By address, clients are counted before anything is done for them; by user, see authenticate().
+                let admitted = match config.rate_limiter.limit_by {
+                    LimitBy::Ip => config.rate_limiter.admit(peer.ip(), None),
+                    LimitBy::User => Ok(()),
                 };
-                let current_dir = match env::current_dir() {
-                    Ok(dir) => match dir.canonicalize() {
-                        Ok(canonical_path) => canonical_path,
-                        Err(_) => return Box::new(InternalServerError{}),
+                let result = match admitted.and_then(|_| config.hosts.root(&parsed)) {
+                    Ok(found) => {
+                        let result = generate_response(&parsed, &mut reader, &found, peer.ip(), &config, &mut entry).await;
+                        root = Some(found);
+                        result
                     },
-                    Err(_) => return Box::new(InternalServerError{}),
-                };
-                if !is_path_ancestor_of(&current_dir, &path) {
-                    return Box::new(NotFound{});
-                }
-                let metadata = match fs::metadata(&path).await {
-                    Ok(data) => data,
-                    Err(_) => return Box::new(NotFound{}),
+                    Err(error) => Err(error),
                 };
-                if metadata.is_dir() {
-                    let friendly_name = match path.strip_prefix(&current_dir) {
-                        Ok(result) => {
-                            match result.to_str() {
-                                Some(result) => result,
-                                None => return Box::new(InternalServerError{}),
-                            }
-                        },
-                        Err(_) => return Box::new(InternalServerError{}),
-                    };
-                    let mut listings = Vec::new();
-                    if path != current_dir {
-                        listings.push(format!(include_str!("../res/listing_entry.html"), path.parent().unwrap().strip_prefix(&current_dir).unwrap().to_str().unwrap(), "..", "-", "-"));
-                    }
-                    for file in path.read_dir().unwrap() {
-                        let file_path = file.unwrap().path();
-                        let file_metadata = fs::metadata(&file_path).await.unwrap();
-                        let created_time = match file_metadata.created() {
-                            Ok(birth_time) => {
-                                let formatted_time: DateTime<Local> = birth_time.into();
-                                formatted_time.format("%d-%b-%Y %H:%M").to_string()
-                            },
-                            Err(_) => "-".to_owned(),
-                        };
-                        let file_size = if file_metadata.is_dir() {
-                            "-".to_owned()
-                        } else {
-                            file_metadata.len().to_string()
-                        };
-                        listings.push(format!(include_str!("../res/listing_entry.html"),
-                            file_path.strip_prefix(&current_dir).unwrap().to_str().unwrap(),
-                            file_path.file_name().unwrap().to_str().unwrap(),
-                            created_time,
-                            file_size,
-                        ))
-                    }
-                    return Box::new(response::Ok {
-                        file_stream: Box::new(stream::iter(vec![Vec::from(
-                                format!(
-                                    include_str!("../res/listing.html"),
-                                    friendly_name,
-                                    friendly_name,
-                                    listings.join("\n"),
-                                ).as_bytes()
-                            )].into_iter().map(|entry| entry.to_owned())))
-                    });
-                } else {
-                    return Box::new(response::Ok{ file_stream: Box::new(ChunkedBufReader::new(BufReader::new(File::open(&path).await.unwrap()))) })
-                }
+                request = Some(parsed);
+                body = Some(reader);
+                result
+            },
+            Err(error) => Err(Error::from(error)),
+        }
+    };
+    let requested_path = request.as_ref().map(HTTPRequest::path).unwrap_or_default();
+    let mut response = match result {
+        Ok(response) => response,
+        Err(error) => {
+            log_error(&stream, &error);
+            match error.status() {
+                Some(status) => {
+                    let mut page = error_page(status, &requested_path, &config).await;
+                    page.headers.extend(error.headers());
+                    Box::new(page)
+                },
+                // Nobody is listening, so there's nothing to say.
+                None => return,
             }
         },
-        _ => return Box::new(NotImplemented{}),
+    };
+    if let Some(request) = &request {
+        config.cors.apply(request, response.headers_mut());
+        if let Some(root) = &root {
+            config.headers.apply(root, &requested_path, response.headers_mut()).await;
+        }
+    }
+    let generated = response.is_generated();
+    config.security.apply(generated, response.headers_mut());
+    let status = response.status_code();
+    let upgrade = response.upgrade();
+    let mut sent = 0;
+    // Once we start writing, the status line may already be with the client, so the only thing
+    // left to do on failure is to drop the connection.
+    let response = config.rate_limiter.throttle(peer.ip(), entry.user.as_deref(), response.response_bytes());
+    let written = match write_response(&mut stream, response, &mut sent).await {
+        Ok(()) => true,
+        Err(error) => {
+            log_error(&stream, &Error::from(error));
+            false
+        },
+    };
+    entry.write(status, sent);
+    // Only a handshake can have an upgrade, and a handshake only comes from a parsed request.
+    if let (true, Some(handler), Some(reader), Some(root)) = (written, upgrade, body, root) {
+        let socket = websocket::WebSocket::new(reader, stream.clone());
+        if let Err(error) = handler(socket, config.clone(), root).await {
+            log_error(&stream, &Error::from(error));
+        }
+    }
+}
+
+async fn error_page(status: Status, requested_path: &str, config: &Config) -> ErrorPage {
+    if let Some(directory) = &config.error_pages {
+        if let Some(page) = ErrorPage::from_directory(status, requested_path, directory).await {
+            return page;
+        }
     }
+    ErrorPage::new(status)
 }
 
-fn is_path_ancestor_of(ancestor: &Path, child: &Path) -> bool {
-    let mut ancestors = child.ancestors();
+async fn write_response(stream: &mut TcpStream, mut response: ByteStream, sent: &mut usize) -> io::Result<()> {
+    let mut writer = BufWriter::new(&mut *stream);
     loop {
-        if let Some(parent) = ancestors.next() {
-            if parent == ancestor {
-                return true;
+        let next = match response.next().now_or_never() {
+            Some(next) => next,
+            // Whatever's buffered goes out before waiting on a slow body, like a proxied stream.
+            None => {
+                writer.flush().await?;
+                response.next().await
+            },
+        };
+        let bytes = match next {
+            Some(bytes) => bytes?,
+            None => break,
+        };
+        writer.write_all(&bytes).await?;
+        *sent += bytes.len();
+    }
+    writer.flush().await?;
+    stream.flush().await
+}
+
+fn log_error(stream: &TcpStream, error: &Error) {
+    match error {
+        Error::NotFound | Error::Hidden | Error::Unauthorized(_) | Error::Refused | Error::TooManyRequests(_) | Error::Forbidden | Error::BadRequest | Error::NotImplemented | Error::UpgradeRequired(_) => return,
+        _ if error.is_disconnect() => return,
+        _ => {},
+    }
+    match stream.peer_addr() {
+        Ok(peer) => eprintln!("{}: {}", peer, error),
+        Err(_) => eprintln!("{}", error),
+    }
+}
+
+async fn generate_response(request: &HTTPRequest, body: &mut RequestBody, root: &Path, peer: IpAddr, config: &Arc<Config>, entry: &mut access_log::Entry) -> Result<Box<dyn Response>> {
+    match serve_request(request, body, root, peer, config, entry).await {
+        Err(Error::NotFound) if wants_spa_fallback(request, config) => {
+            // Hidden paths get the 404 whether they exist or not, so as not to give away which do.
+            if !config.policy.permits(root, &policy::normalize(&request.requested_path)?, false).await {
+                return Err(Error::NotFound);
+            }
+            // The entry point has to get past the same rules as when it's asked for by name, and
+            // resolve_visible checks its credentials. A site whose fallback has since gone away
+            // gets the 404.
+            let fallback = policy::normalize(&config.spa_fallback)?;
+            if !config.ip_filter.permits_path(peer, &fallback) {
+                return Err(config.ip_filter.refusal());
+            }
+            let (_, path, metadata) = resolve_visible(&config.spa_fallback, request, root, peer, config, entry).await?;
+            if metadata.is_dir() {
+                return Err(Error::NotFound);
             }
-        } else {
-            return false;
+            let mut response = serve_file(&path, config).await?;
+            response.headers_mut().push(("Vary".to_owned(), "Accept".to_owned()));
+            Ok(response)
+        },
+        result => result,
+    }
+}
+
+/*
+* Client-side routes like /settings/profile don't exist on disk, so single-page apps want their
+* entry point for any page the browser navigates to. Paths with an extension look like assets,
+* which should still 404 when missing, and hidden paths aren't missing.
+*/
+fn wants_spa_fallback(request: &HTTPRequest, config: &Config) -> bool {
+    !config.spa_fallback.is_empty()
+        && matches!(request.method, Method::GET)
+        && request.accept_quality("text/html") > 0.0
+        && request.requested_path.iter().rev().find(|segment| !segment.is_empty()).is_none_or(|segment| Path::new(segment).extension().is_none())
+}
+
+/*
+* Check the client's credentials for `path`, relative to the root. Limited by user, clients are
+* counted here, once it's known who they are but before anything is done for them, and failed
+* attempts count against the address. What's answered before this, like redirects and preflight
+* requests, is cheap and isn't counted.
+*/
+async fn authenticate(path: &Path, request: &HTTPRequest, peer: IpAddr, config: &Config, entry: &mut access_log::Entry) -> Result<()> {
+    let user = config.auth.check(path, request).await;
+    if config.rate_limiter.limit_by == LimitBy::User {
+        config.rate_limiter.admit(peer, user.as_ref().ok().and_then(Option::as_deref))?;
+    }
+    entry.user = user?;
+    Ok(())
+}
+
+async fn serve_request(request: &HTTPRequest, body: &mut RequestBody, root: &Path, peer: IpAddr, config: &Arc<Config>, entry: &mut access_log::Entry) -> Result<Box<dyn Response>> {
+    let rewritten;
+    let request = match config.redirects.evaluate(root, request).await {
+        Some(Outcome::Redirect(redirect)) => return Ok(Box::new(redirect)),
+        Some(Outcome::Rewrite(rewrite)) => {
+            rewritten = rewrite;
+            &rewritten
+        },
+        None => request,
+    };
+    // Turn clients away before anything about the filesystem gives away what exists.
+    let normalized = policy::normalize(&request.requested_path)?;
+    if !config.ip_filter.permits_path(peer, &normalized) {
+        return Err(config.ip_filter.refusal());
+    }
+    // Preflight requests never carry credentials, so they're answered before asking for any.
+    if let Some(preflight) = config.cors.preflight(request) {
+        return Ok(Box::new(preflight));
+    }
+    // Whatever the method, proxied requests are upstream's business once they're past our rules.
+    if let Some(route) = config.proxy.route(&normalized) {
+        authenticate(&normalized, request, peer, config, entry).await?;
+        return config.proxy.forward(route, request, peer, body, config.auth.covers(&normalized)).await;
+    }
+    // An encoded slash can't name anything on the filesystem, though it might upstream.
+    if request.requested_path.iter().any(|segment| segment.contains('/')) {
+        return Err(Error::BadRequest);
+    }
+    match request.method {
+        Method::GET => {},
+        _ => return Err(Error::NotImplemented),
+    }
+    authenticate(&normalized, request, peer, config, entry).await?;
+    // Other upgrades, and WebSockets elsewhere, are ignored; the request is served as usual.
+    if websocket::is_upgrade(request) {
+        if let Some(handler) = config.websockets.get(&request.path()) {
+            return Ok(Box::new(websocket::handshake(request, handler)?));
         }
     }
+    if config.live_reload.enabled {
+        match request.path().as_str() {
+            live_reload::EVENTS_PATH => return Ok(Box::new(config.live_reload.events(root))),
+            live_reload::SCRIPT_PATH => return Ok(Box::new(config.live_reload.script())),
+            _ => {},
+        }
+    }
+    let (requested, path, metadata) = resolve_visible(&request.requested_path, request, root, peer, config, entry).await?;
+    // "/docs/" parses with an empty last segment; "/" is just that empty segment.
+    let has_trailing_slash = request.requested_path.last().is_some_and(|segment| segment.is_empty());
+    if metadata.is_dir() {
+        if !has_trailing_slash {
+            // Relative links only work from the canonical, slashed URL of a directory.
+            let mut location: String = request.requested_path.iter().map(|segment| format!("/{}", percent_encode(segment))).collect();
+            location.push('/');
+            if let Some(query) = &request.query {
+                location.push('?');
+                location.push_str(query);
+            }
+            return Ok(Box::new(Redirect{ kind: RedirectKind::MovedPermanently, location, headers: Vec::new() }));
+        }
+        // An archive shows everything a listing would, and more.
+        if let Some(format) = archive::Format::from_request(request) {
+            if !config.listings {
+                return Err(Error::Forbidden);
+            }
+            return Ok(Box::new(archive::download(format, path, requested, request, peer, root, config).await));
+        }
+        let rules = config.policy.rules_for(root, &requested).await;
+        for index_file in &config.index_files {
+            let index_path = path.join(index_file);
+            if let Ok(index_metadata) = fs::metadata(&index_path).await {
+                if index_metadata.is_file() && rules.permits(&requested.join(index_file), false) {
+                    return serve_file(&index_path, config).await;
+                }
+            }
+        }
+        if !config.listings {
+            return Err(Error::Forbidden);
+        }
+        // Built from request segments, which are always UTF-8.
+        let friendly_name = requested.to_str().unwrap_or_default();
+        let mut entries = listing::read_entries(&path, &requested, &rules).await?;
+        let sort = Sort::from_request(request);
+        sort.apply(&mut entries);
+        let format = Format::from_request(request);
+        Ok(Box::new(response::Ok {
+            headers: vec![
+                ("Content-Type".to_owned(), format.content_type().to_owned()),
+                ("Vary".to_owned(), "Accept".to_owned()),
+            ],
+            file_stream: Box::new(stream::iter(vec![Ok(Vec::from(
+                    format.render(friendly_name, !friendly_name.is_empty(), &entries, &sort, &config.templates).as_bytes()
+                ))])),
+            generated: true,
+        }))
+    } else if has_trailing_slash {
+        Err(Error::NotFound)
+    } else {
+        serve_file(&path, config).await
+    }
+}
+
+/*
+* The file `segments` lead to under `root`, as the path asked for relative to the root, the
+* canonical path and its metadata, as long as the client may see it.
+*/
+async fn resolve_visible(segments: &[String], request: &HTTPRequest, root: &Path, peer: IpAddr, config: &Config, entry: &mut access_log::Entry) -> Result<(PathBuf, PathBuf, fs::Metadata)> {
+    let (requested, path) = config.policy.resolve(root, segments).await?;
+    let metadata = fs::metadata(&path).await.map_err(|_| Error::NotFound)?;
+    // A link into a protected part of the tree needs to get past that part's rules too.
+    if let Ok(relative) = path.strip_prefix(root) {
+        if !config.ip_filter.permits_path(peer, relative) {
+            return Err(config.ip_filter.refusal());
+        }
+        if let Some(user) = config.auth.check(relative, request).await? {
+            entry.user = Some(user);
+        }
+    }
+    // Both the name asked for and the file it resolves to have to be visible.
+    let permits_resolved = match path.strip_prefix(root) {
+        Ok(relative) => config.policy.permits(root, relative, metadata.is_dir()).await,
+        Err(_) => true,
+    };
+    if !permits_resolved || !config.policy.permits(root, &requested, metadata.is_dir()).await {
+        return Err(Error::Hidden);
+    }
+    Ok((requested, path, metadata))
+}
+
+async fn serve_file(path: &Path, config: &Config) -> Result<Box<dyn Response>> {
+    let media_type = mime::content_type(path);
+    let headers = media_type.map(|media_type| ("Content-Type".to_owned(), media_type.to_owned())).into_iter().collect();
+    let mut file_stream: ByteStream = Box::new(ChunkedBufReader::new(BufReader::new(File::open(path).await?)));
+    if config.live_reload.enabled && media_type.is_some_and(|media_type| media_type.starts_with("text/html")) {
+        file_stream = config.live_reload.inject(file_stream);
+    }
+    Ok(Box::new(response::Ok{ headers, file_stream, generated: false }))
 }
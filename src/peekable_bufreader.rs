@@ -1,3 +1,5 @@
+use std::io;
+
 use async_std::prelude::*;
 use async_std::io::BufReader;
 use async_std::io::Read;
@@ -7,6 +9,10 @@ pub struct PeekableBufReader<T>
     reader: BufReader<T>,
     buffer: [u8; 1],
     peeked_last: bool,
+    position: usize,
+    limit: usize,
+    exhausted: bool,
+    hit_limit: bool,
 }
 
 impl<T> PeekableBufReader<T>
@@ -16,31 +22,82 @@ impl<T> PeekableBufReader<T>
             reader,
             buffer: [0],
             peeked_last: false,
+            position: 0,
+            limit: usize::MAX,
+            exhausted: false,
+            hit_limit: false,
         }
     }
 
+    /// The underlying reader, e.g. to write an interim response to the stream being read.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.reader.get_mut()
+    }
+
+    /// The number of bytes consumed so far.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// Stop yielding bytes once `limit` bytes in total have been consumed.
+    pub fn set_limit(&mut self, limit: usize) {
+        self.limit = limit;
+        self.hit_limit = false;
+    }
+
+    /// Whether the underlying reader has reached end-of-file or failed.
+    pub fn is_exhausted(&self) -> bool {
+        self.exhausted
+    }
+
+    /// Whether a read was refused because of the limit set with `set_limit`.
+    pub fn is_over_limit(&self) -> bool {
+        self.hit_limit
+    }
+
     pub async fn next(&mut self) -> Option<u8> {
-        if self.peeked_last {
+        if self.peek().await.is_some() {
             self.peeked_last = false;
+            self.position += 1;
             Some(self.buffer[0])
         } else {
-            match self.reader.read(&mut self.buffer).await.ok()? {
-                1 => Some(self.buffer[0]),
-                _ => None,
-            }
+            None
+        }
+    }
+
+    /// Read whatever comes next into `buf`, in bulk and regardless of the limit, e.g. a message body.
+    pub async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
         }
+        let size = if self.peeked_last {
+            self.peeked_last = false;
+            buf[0] = self.buffer[0];
+            1
+        } else {
+            self.reader.read(buf).await?
+        };
+        self.position += size;
+        Ok(size)
     }
 
     pub async fn peek(&mut self) -> Option<&u8> {
+        if self.position >= self.limit {
+            self.hit_limit = true;
+            return None;
+        }
         if self.peeked_last {
             Some(&self.buffer[0])
         } else {
-            match self.reader.read(&mut self.buffer).await.ok()? {
-                1 => {
+            match self.reader.read(&mut self.buffer).await {
+                Ok(1) => {
                     self.peeked_last = true;
                     Some(&self.buffer[0])
                 },
-                _ => None,
+                _ => {
+                    self.exhausted = true;
+                    None
+                },
             }
         }
     }
@@ -0,0 +1,236 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use async_std::fs;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+
+use crate::custom_headers::HEADERS_FILE;
+use crate::error::{Error, Result};
+use crate::redirects::REDIRECTS_FILE;
+use crate::watched_file::WatchedFile;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Symlinks {
+    // Follow links as long as they lead somewhere under the root.
+    Root,
+    // Follow links wherever they lead.
+    Any,
+    // Refuse any path that passes through a link.
+    Never,
+}
+
+impl Symlinks {
+    pub fn from_str(string: &str) -> Option<Symlinks> {
+        match string {
+            "root" => Some(Symlinks::Root),
+            "any" => Some(Symlinks::Any),
+            "never" => Some(Symlinks::Never),
+            _ => None,
+        }
+    }
+}
+
+/*
+* The path that the segments of a request path lead to, with "." and ".." resolved lexically. An
+* encoded slash separates names like any other, since no one name can contain it.
+*/
+pub fn normalize(segments: &[String]) -> Result<PathBuf> {
+    let mut requested = PathBuf::new();
+    for segment in segments.iter().flat_map(|segment| segment.split('/')) {
+        match segment {
+            "" | "." => {},
+            ".." => if !requested.pop() {
+                return Err(Error::NotFound);
+            },
+            segment => requested.push(segment),
+        }
+    }
+    Ok(requested)
+}
+
+/*
+* Decides which files under the root are listed and served. In order of precedence:
+* - anything matching an allow pattern is visible;
+* - anything with a name starting with '.', and the _headers and _redirects files in the root, are
+*   hidden, unless hidden files are shown;
+* - anything matching a deny pattern is hidden;
+* - anything ignored by a .gitignore on the way down from the root is hidden, if enabled.
+* Patterns are matched against the path relative to the root, and against each of its ancestors,
+* so hiding a directory hides everything in it.
+* Symbolic links are followed according to `symlinks`.
+*/
+pub struct Policy {
+    symlinks: Symlinks,
+    show_hidden: bool,
+    allow: GlobSet,
+    deny: GlobSet,
+    gitignore: bool,
+    // By directory, so each is only parsed again when it changes.
+    gitignores: WatchedFile<Gitignore>,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            symlinks: Symlinks::Root,
+            show_hidden: false,
+            allow: GlobSet::empty(),
+            deny: GlobSet::empty(),
+            gitignore: false,
+            gitignores: WatchedFile::new(".gitignore", Self::parse_gitignore),
+        }
+    }
+}
+
+impl Policy {
+    pub fn new(symlinks: Symlinks, show_hidden: bool, allow: &[String], deny: &[String], gitignore: bool) -> std::result::Result<Self, String> {
+        Ok(Self {
+            symlinks,
+            show_hidden,
+            allow: Self::glob_set(allow)?,
+            deny: Self::glob_set(deny)?,
+            gitignore,
+            gitignores: WatchedFile::new(".gitignore", Self::parse_gitignore),
+        })
+    }
+
+    // A partially broken .gitignore still has its valid lines applied.
+    fn parse_gitignore(path: &Path, contents: &str) -> Gitignore {
+        let mut builder = GitignoreBuilder::new(path.parent().unwrap_or_else(|| Path::new("")));
+        for line in contents.lines() {
+            if let Err(e) = builder.add_line(Some(path.to_path_buf()), line) {
+                eprintln!("{}: {}", path.display(), e);
+            }
+        }
+        builder.build().unwrap_or_else(|e| {
+            eprintln!("{}: {}", path.display(), e);
+            Gitignore::empty()
+        })
+    }
+
+    fn glob_set(patterns: &[String]) -> std::result::Result<GlobSet, String> {
+        let mut builder = GlobSetBuilder::new();
+        for pattern in patterns {
+            builder.add(Glob::new(pattern).map_err(|e| e.to_string())?);
+        }
+        builder.build().map_err(|e| e.to_string())
+    }
+
+    /*
+    * Map the segments of a request path onto the filesystem under `root`, which must be
+    * canonical. Returns the requested path relative to the root, with "." and ".." resolved
+    * lexically, and the file it leads to.
+    */
+    pub async fn resolve(&self, root: &Path, segments: &[String]) -> Result<(PathBuf, PathBuf)> {
+        let requested = normalize(segments)?;
+        let path = root.join(&requested);
+        let resolved = match self.symlinks {
+            Symlinks::Never => {
+                let mut current = root.to_path_buf();
+                for component in requested.components() {
+                    current.push(component);
+                    let metadata = fs::symlink_metadata(&current).await.map_err(|_| Error::NotFound)?;
+                    if metadata.file_type().is_symlink() {
+                        return Err(Error::SymlinkRefused(current, "links are never followed"));
+                    }
+                }
+                // No links on the way down from a canonical root, so this is canonical too.
+                path
+            },
+            Symlinks::Root => {
+                let resolved: PathBuf = fs::canonicalize(&path).await.map_err(|_| Error::NotFound)?.into();
+                if !resolved.starts_with(root) {
+                    return Err(Error::SymlinkRefused(path, "the target is outside the root"));
+                }
+                resolved
+            },
+            Symlinks::Any => fs::canonicalize(&path).await.map_err(|_| Error::NotFound)?.into(),
+        };
+        Ok((requested, resolved))
+    }
+
+    // Whether a link may be followed to `target`, given as a canonical path.
+    pub fn follows(&self, root: &Path, target: &Path) -> bool {
+        match self.symlinks {
+            Symlinks::Root => target.starts_with(root),
+            Symlinks::Any => true,
+            Symlinks::Never => false,
+        }
+    }
+
+    // The rules in effect for entries of `directory`, given relative to `root`.
+    pub async fn rules_for(&self, root: &Path, directory: &Path) -> Rules<'_> {
+        let mut gitignores = Vec::new();
+        if self.gitignore {
+            let mut current = root.to_path_buf();
+            gitignores.extend(self.gitignores.get(&current).await);
+            for component in directory.components() {
+                current.push(component);
+                gitignores.extend(self.gitignores.get(&current).await);
+            }
+        }
+        Rules {
+            policy: self,
+            root: root.to_path_buf(),
+            gitignores,
+        }
+    }
+
+    // Whether `path`, relative to the root, may be listed and served.
+    pub async fn permits(&self, root: &Path, path: &Path, is_dir: bool) -> bool {
+        self.rules_for(root, path.parent().unwrap_or_else(|| Path::new(""))).await.permits(path, is_dir)
+    }
+}
+
+pub struct Rules<'a> {
+    policy: &'a Policy,
+    root: PathBuf,
+    // From the root down, so later ones take precedence.
+    gitignores: Vec<Arc<Gitignore>>,
+}
+
+impl Rules<'_> {
+    // Whether a link to `target`, given as a canonical path, may be listed and followed.
+    pub async fn permits_target(&self, target: &Path, is_dir: bool) -> bool {
+        if !self.policy.follows(&self.root, target) {
+            return false;
+        }
+        match target.strip_prefix(&self.root) {
+            Ok(relative) => self.policy.permits(&self.root, relative, is_dir).await,
+            Err(_) => true,
+        }
+    }
+
+    // Whether `path`, relative to the root, may be listed and served.
+    pub fn permits(&self, path: &Path, is_dir: bool) -> bool {
+        let mut prefix = PathBuf::new();
+        for component in path.components() {
+            prefix.push(component);
+            if self.policy.allow.is_match(&prefix) {
+                continue;
+            }
+            // The server's own configuration says more about the site than it means to show.
+            let hidden = prefix.file_name().and_then(|name| name.to_str()).is_some_and(|name| name.starts_with('.'))
+                || prefix == Path::new(HEADERS_FILE)
+                || prefix == Path::new(REDIRECTS_FILE);
+            if (hidden && !self.policy.show_hidden) || self.policy.deny.is_match(&prefix) {
+                return false;
+            }
+        }
+        if path.as_os_str().is_empty() || self.policy.allow.is_match(path) {
+            return true;
+        }
+        let absolute = self.root.join(path);
+        let mut ignored = false;
+        for gitignore in &self.gitignores {
+            let matched = gitignore.matched_path_or_any_parents(&absolute, is_dir);
+            if matched.is_ignore() {
+                ignored = true;
+            } else if matched.is_whitelist() {
+                ignored = false;
+            }
+        }
+        !ignored
+    }
+}
@@ -0,0 +1,546 @@
+use std::cmp;
+use std::future::Future;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use async_std::prelude::*;
+use async_std::io::{self, BufRead, BufReader, Read, Write};
+use async_std::net::TcpStream;
+use async_std::os::unix::net::UnixStream;
+use futures::stream;
+
+use crate::error::{Error, Result};
+use crate::http::{HTTPRequest, Version};
+use crate::peekable_bufreader::PeekableBufReader;
+use crate::policy;
+use crate::response::{self, Response, ByteStream};
+
+const CHUNK_SIZE: usize = 4096;
+// Longer chunk-size lines from the client get a 400.
+const MAX_LINE_LENGTH: usize = 4096;
+// Longer response heads from upstream get a 502.
+const MAX_HEAD_LENGTH: u64 = 65536;
+
+/*
+* RFC 7230, Page 52
+* Hop-by-hop header fields, which only concern the connection they came over. Transfer-Encoding is
+* one too, but bodies are passed along as they are, so it's dealt with separately.
+*/
+const HOP_BY_HOP: &[&str] = &["connection", "keep-alive", "proxy-connection", "te", "trailer", "upgrade"];
+
+pub enum Upstream {
+    // host:port
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+pub struct Route {
+    prefix: PathBuf,
+    upstream: Upstream,
+}
+
+// How the request body, if any, is delimited.
+enum Framing {
+    Empty,
+    Length(u64),
+    Chunked,
+}
+
+/*
+* Forwards requests under path prefixes to other servers, like a backend in development, so pages
+* and their API share an origin. The longest matching prefix wins, and is taken off the path, which
+* is otherwise passed on as it was sent, but for dot-segments.
+*/
+pub struct Proxy {
+    routes: Vec<Route>,
+    pub connect_timeout: Duration,
+    // How long upstream may stay silent, waiting for the response or in the middle of its body.
+    pub read_timeout: Duration,
+}
+
+impl Default for Proxy {
+    fn default() -> Self {
+        Self {
+            routes: Vec::new(),
+            connect_timeout: Duration::from_secs(5),
+            read_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+impl Proxy {
+    // Add a route given as PREFIX=HOST:PORT or PREFIX=unix:PATH.
+    pub fn add(&mut self, spec: &str) -> std::result::Result<(), String> {
+        let (prefix, upstream) = spec.split_once('=').ok_or_else(|| format!("expected PREFIX=HOST:PORT or PREFIX=unix:PATH, got {}", spec))?;
+        let segments: Vec<String> = prefix.split('/').map(str::to_owned).collect();
+        let prefix = policy::normalize(&segments).map_err(|_| format!("{} leads outside the root", prefix))?;
+        let upstream = match upstream.strip_prefix("unix:") {
+            Some(path) => Upstream::Unix(PathBuf::from(path)),
+            None => match upstream.rsplit_once(':') {
+                Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => Upstream::Tcp(upstream.to_owned()),
+                _ => return Err(format!("{} is not HOST:PORT or unix:PATH", upstream)),
+            },
+        };
+        self.routes.push(Route { prefix, upstream });
+        Ok(())
+    }
+
+    // The route requests for `path`, relative to the root, take, if not to the filesystem.
+    pub fn route(&self, path: &Path) -> Option<&Route> {
+        self.routes.iter()
+            .filter(|route| path.starts_with(&route.prefix))
+            .max_by_key(|route| route.prefix.components().count())
+    }
+
+    /*
+    * Send `request`, and its body from `body`, along `route`, and return the response to stream
+    * back. The request carries X-Forwarded-For, -Host and -Proto, and asks upstream to close the
+    * connection after responding, which is how its body ends when nothing else says so.
+    * Credentials for our own realm, when `authenticated`, are none of upstream's business.
+    */
+    pub async fn forward<T>(&self, route: &Route, request: &HTTPRequest, peer: IpAddr, body: &mut PeekableBufReader<T>, authenticated: bool) -> Result<Box<dyn Response>>
+        where T: Read + Write + Unpin {
+        let framing = Self::framing(request)?;
+        let head = Self::request_head(route, request, peer, &framing, authenticated);
+        /* RFC 7231, Page 34: HTTP/1.0 clients don't know about 100 Continue. */
+        let expects_continue = matches!(request.version, Version::Http1_1)
+            && request.header("expect").is_some_and(|expect| expect.trim().eq_ignore_ascii_case("100-continue"));
+        match &route.upstream {
+            Upstream::Tcp(address) => {
+                let connection = self.connect(TcpStream::connect(address.as_str())).await?;
+                self.exchange(connection, &head, &framing, body, expects_continue).await
+            },
+            Upstream::Unix(path) => {
+                let connection = self.connect(UnixStream::connect(path)).await?;
+                self.exchange(connection, &head, &framing, body, expects_continue).await
+            },
+        }
+    }
+
+    async fn connect<S>(&self, connecting: impl Future<Output = io::Result<S>>) -> Result<S> {
+        io::timeout(self.connect_timeout, connecting).await.map_err(upstream_error)
+    }
+
+    async fn exchange<S, T>(&self, mut connection: S, head: &[u8], framing: &Framing, body: &mut PeekableBufReader<T>, expects_continue: bool) -> Result<Box<dyn Response>>
+        where S: Read + Write + Unpin + Send + Sync + 'static, T: Read + Write + Unpin {
+        connection.write_all(head).await.map_err(upstream_error)?;
+        /*
+        * RFC 7231, Page 34
+        * Expect isn't passed on, since the body goes out right behind the head, so it's up to us
+        * to tell a waiting client to send it, now that there's somewhere for it to go.
+        */
+        if expects_continue && !matches!(framing, Framing::Empty) {
+            let client = body.get_mut();
+            client.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await?;
+            client.flush().await?;
+        }
+        match framing {
+            Framing::Empty => {},
+            Framing::Length(length) => copy_exact(body, &mut connection, *length).await?,
+            Framing::Chunked => copy_chunked(body, &mut connection).await?,
+        }
+        connection.flush().await.map_err(upstream_error)?;
+        let mut reader = BufReader::new(connection);
+        // Interim responses, like 100 Continue, are ours to deal with, not the client's.
+        let (status, reason, headers) = loop {
+            let head = io::timeout(self.read_timeout, async {
+                read_head(&mut reader).await.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            }).await.map_err(upstream_error)?;
+            if !(100..200).contains(&head.0) {
+                break head;
+            }
+        };
+        let read_timeout = self.read_timeout;
+        let body = stream::unfold(Some(reader), move |reader| async move {
+            let mut reader = reader?;
+            let mut chunk = vec![0; CHUNK_SIZE];
+            match io::timeout(read_timeout, reader.read(&mut chunk)).await {
+                Ok(0) => None,
+                Ok(size) => {
+                    chunk.truncate(size);
+                    Some((Ok(chunk), Some(reader)))
+                },
+                Err(e) => Some((Err(e), None)),
+            }
+        });
+        Ok(Box::new(Proxied { status, reason, headers, body: Box::new(Box::pin(body)) }))
+    }
+
+    /*
+    * RFC 7230, Page 32
+    * A body is delimited by a chunked transfer coding or a Content-Length. Any other transfer
+    * coding gets a 501, since there'd be no telling where the body ends. A message with both, or
+    * with Content-Lengths that differ, gets a 400: upstream might read it differently from us, and
+    * take what's left of the body for a request of its own.
+    */
+    fn framing(request: &HTTPRequest) -> Result<Framing> {
+        let fields = |wanted: &'static str| request.fields.iter()
+            .filter(move |(name, _)| name.eq_ignore_ascii_case(wanted))
+            .map(|(_, value)| value.as_slice());
+        let codings: Vec<&[u8]> = fields("transfer-encoding").collect();
+        let mut lengths = fields("content-length")
+            .flat_map(|value| value.split(|&byte| byte == b','))
+            .map(|length| std::str::from_utf8(length).ok().map(str::trim).filter(|length| length.bytes().all(|byte| byte.is_ascii_digit())).and_then(|length| length.parse().ok()));
+        match codings[..] {
+            [] => {},
+            _ if lengths.next().is_some() => return Err(Error::BadRequest),
+            [coding] if coding.eq_ignore_ascii_case(b"chunked") => return Ok(Framing::Chunked),
+            _ => return Err(Error::NotImplemented),
+        }
+        let length = match lengths.next() {
+            Some(length) => length.ok_or(Error::BadRequest)?,
+            None => return Ok(Framing::Empty),
+        };
+        if lengths.any(|other| other != Some(length)) {
+            return Err(Error::BadRequest);
+        }
+        Ok(Framing::Length(length))
+    }
+
+    /*
+    * RFC 3986, Page 33
+    * The path as sent, after the route's prefix, with dot-segments removed the way the path was
+    * resolved when choosing the route: encoded slashes separate names too, and ".." takes out the
+    * last name that isn't empty.
+    */
+    fn target(route: &Route, request: &HTTPRequest) -> String {
+        // Each name as sent, and whether it follows an encoded slash rather than a real one.
+        let mut names: Vec<(String, bool)> = Vec::new();
+        let mut directory = false;
+        for (segment, raw) in request.requested_path.iter().zip(&request.raw_path) {
+            let raw = raw.replace("%2f", "%2F");
+            for (index, (name, raw)) in segment.split('/').zip(raw.split("%2F")).enumerate() {
+                directory = name == "." || name == "..";
+                match name {
+                    "." => {},
+                    ".." => {
+                        while names.last().is_some_and(|(name, _)| name.is_empty()) {
+                            names.pop();
+                        }
+                        names.pop();
+                    },
+                    _ => names.push((raw.to_owned(), index > 0)),
+                }
+            }
+        }
+        // "/docs/." and "/docs/x/.." are both "/docs/".
+        if directory {
+            names.push((String::new(), false));
+        }
+        let mut prefix = route.prefix.components().count();
+        let mut rest = &names[..];
+        while let Some(((name, _), others)) = rest.split_first().filter(|_| prefix > 0) {
+            if !name.is_empty() {
+                prefix -= 1;
+            }
+            rest = others;
+        }
+        let mut target = String::from("/");
+        for (index, (name, encoded)) in rest.iter().enumerate() {
+            if index > 0 {
+                target.push_str(if *encoded { "%2F" } else { "/" });
+            }
+            target.push_str(name);
+        }
+        target
+    }
+
+    fn request_head(route: &Route, request: &HTTPRequest, peer: IpAddr, framing: &Framing, authenticated: bool) -> Vec<u8> {
+        let mut target = Self::target(route, request);
+        if let Some(query) = &request.query {
+            target.push('?');
+            target.push_str(query);
+        }
+        // HTTP/1.0 clients can't take a chunked response, so upstream had better not send one.
+        let version = match request.version {
+            Version::Http1_1 => "HTTP/1.1",
+            _ => "HTTP/1.0",
+        };
+        let mut head = format!("{} {} {}\r\n", request.method.as_str(), target, version).into_bytes();
+        let connection_options = connection_options(request.header("connection").unwrap_or_default());
+        let mut forwarded_for = peer.to_string();
+        // Each line as it came, since not every repeated field can be joined into one.
+        for (name, value) in &request.fields {
+            match name.to_ascii_lowercase().as_str() {
+                "x-forwarded-for" => {
+                    forwarded_for = format!("{}, {}", String::from_utf8_lossy(value), peer);
+                    continue;
+                },
+                // We know better; and upstream would answer Expect itself, after we've already sent the body.
+                "x-forwarded-host" | "x-forwarded-proto" | "expect" | "transfer-encoding" | "content-length" => continue,
+                "authorization" if authenticated => continue,
+                name if HOP_BY_HOP.contains(&name) || connection_options.iter().any(|option| option == name) => continue,
+                _ => {},
+            }
+            head.extend_from_slice(name.as_bytes());
+            head.extend_from_slice(b": ");
+            head.extend_from_slice(value);
+            head.extend_from_slice(b"\r\n");
+        }
+        let mut extra = format!("X-Forwarded-For: {}\r\nX-Forwarded-Proto: http\r\n", forwarded_for);
+        if let Some(host) = request.header("host") {
+            extra.push_str(&format!("X-Forwarded-Host: {}\r\n", host));
+        }
+        match framing {
+            Framing::Empty => {},
+            Framing::Length(length) => extra.push_str(&format!("Content-Length: {}\r\n", length)),
+            Framing::Chunked => extra.push_str("Transfer-Encoding: chunked\r\n"),
+        }
+        extra.push_str("Connection: close\r\n\r\n");
+        head.extend_from_slice(extra.as_bytes());
+        head
+    }
+}
+
+// A response from upstream, passed along as it comes.
+struct Proxied {
+    status: u16,
+    reason: String,
+    headers: Vec<(String, String)>,
+    body: ByteStream,
+}
+
+impl Response for Proxied {
+    fn status_code(&self) -> u16 {
+        self.status
+    }
+
+    fn headers_mut(&mut self) -> &mut Vec<(String, String)> {
+        &mut self.headers
+    }
+
+    fn response_bytes(self: Box<Self>) -> ByteStream {
+        let Proxied { status, reason, headers, body } = *self;
+        let head = response::head(&format!("{} {}", status, reason), &headers);
+        Box::new(stream::iter(vec![Ok(head)]).chain(body))
+    }
+}
+
+// Timing out is a 504; anything else going wrong upstream is a 502.
+fn upstream_error(error: io::Error) -> Error {
+    match error.kind() {
+        io::ErrorKind::TimedOut => Error::GatewayTimeout,
+        _ => Error::BadGateway(error.to_string()),
+    }
+}
+
+// The lowercase field names listed in a Connection header, which are hop-by-hop too.
+fn connection_options(connection: &str) -> Vec<String> {
+    connection.split(',').map(|option| option.trim().to_ascii_lowercase()).filter(|option| !option.is_empty()).collect()
+}
+
+/*
+* RFC 7230, Page 19
+* The status code, reason phrase and end-to-end header fields of a response.
+*/
+async fn read_head<R>(reader: &mut R) -> std::result::Result<(u16, String, Vec<(String, String)>), String>
+    where R: BufRead + Unpin {
+    let mut reader = reader.take(MAX_HEAD_LENGTH);
+    let mut lines = Vec::new();
+    loop {
+        let mut line = Vec::new();
+        reader.read_until(b'\n', &mut line).await.map_err(|e| e.to_string())?;
+        if !line.ends_with(b"\n") {
+            return Err("response head too long or cut short".to_owned());
+        }
+        let line = String::from_utf8_lossy(&line).trim_end_matches(&['\r', '\n'][..]).to_owned();
+        if line.is_empty() {
+            break;
+        }
+        lines.push(line);
+    }
+    let mut lines = lines.into_iter();
+    let status_line = lines.next().ok_or("empty response head")?;
+    let (status, reason) = match status_line.splitn(3, ' ').collect::<Vec<&str>>()[..] {
+        [version, status, ref reason @ ..] if version.starts_with("HTTP/1.") && status.len() == 3 => {
+            (status.parse::<u16>().map_err(|_| format!("invalid status line {}", status_line))?, reason.first().copied().unwrap_or_default().to_owned())
+        },
+        _ => return Err(format!("invalid status line {}", status_line)),
+    };
+    let mut headers = Vec::new();
+    for line in lines {
+        let (name, value) = line.split_once(':').ok_or_else(|| format!("invalid header field {}", line))?;
+        headers.push((name.trim().to_owned(), value.trim().to_owned()));
+    }
+    let connection = headers.iter().filter(|(name, _)| name.eq_ignore_ascii_case("connection")).map(|(_, value)| value.as_str()).collect::<Vec<&str>>().join(",");
+    let connection_options = connection_options(&connection);
+    headers.retain(|(name, _)| {
+        let name = name.to_ascii_lowercase();
+        !HOP_BY_HOP.contains(&name.as_str()) && !connection_options.contains(&name)
+    });
+    Ok((status, reason, headers))
+}
+
+// A line of the request body, without its line ending.
+async fn read_line<T>(body: &mut PeekableBufReader<T>) -> Result<Vec<u8>>
+    where T: Read + Unpin {
+    body.set_limit(body.position() + MAX_LINE_LENGTH);
+    let mut line = Vec::new();
+    loop {
+        match body.next().await {
+            Some(b'\n') => break,
+            Some(byte) => line.push(byte),
+            None => return Err(Error::BadRequest),
+        }
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(line)
+}
+
+// Pass `length` bytes of the request body to upstream.
+async fn copy_exact<T, S>(body: &mut PeekableBufReader<T>, upstream: &mut S, length: u64) -> Result<()>
+    where T: Read + Unpin, S: Write + Unpin {
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let mut remaining = length;
+    while remaining > 0 {
+        let wanted = cmp::min(remaining, CHUNK_SIZE as u64) as usize;
+        let size = body.read(&mut buffer[..wanted]).await?;
+        if size == 0 {
+            // The client hung up partway.
+            return Err(Error::BadRequest);
+        }
+        upstream.write_all(&buffer[..size]).await.map_err(upstream_error)?;
+        remaining -= size as u64;
+    }
+    Ok(())
+}
+
+/*
+* RFC 7230, Page 36
+* Pass a chunked request body to upstream as it is, following the chunk sizes to find its end.
+*/
+async fn copy_chunked<T, S>(body: &mut PeekableBufReader<T>, upstream: &mut S) -> Result<()>
+    where T: Read + Unpin, S: Write + Unpin {
+    loop {
+        let line = read_line(body).await?;
+        let size = std::str::from_utf8(&line).ok()
+            .and_then(|line| u64::from_str_radix(line.split(';').next().unwrap_or_default().trim(), 16).ok())
+            .ok_or(Error::BadRequest)?;
+        upstream.write_all(&[&line[..], b"\r\n"].concat()).await.map_err(upstream_error)?;
+        if size == 0 {
+            break;
+        }
+        copy_exact(body, upstream, size).await?;
+        if !read_line(body).await?.is_empty() {
+            return Err(Error::BadRequest);
+        }
+        upstream.write_all(b"\r\n").await.map_err(upstream_error)?;
+    }
+    // The trailer section, up to and including the empty line.
+    loop {
+        let line = read_line(body).await?;
+        upstream.write_all(&[&line[..], b"\r\n"].concat()).await.map_err(upstream_error)?;
+        if line.is_empty() {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::io::BufReader;
+    use async_std::task;
+
+    use super::*;
+    use crate::http::Parser;
+
+    fn parse(message: &str) -> HTTPRequest {
+        let mut parser = Parser::new(PeekableBufReader::new(BufReader::new(message.as_bytes())));
+        task::block_on(parser.parse()).expect("the request should parse")
+    }
+
+    // The target a request for `target` goes upstream with, along a route for `prefix`.
+    fn forwarded(prefix: &str, target: &str) -> String {
+        let mut proxy = Proxy::default();
+        proxy.add(&format!("{}=localhost:1", prefix)).unwrap();
+        let request = parse(&format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", target));
+        let route = proxy.route(&policy::normalize(&request.requested_path).unwrap()).expect("the route should match");
+        Proxy::target(route, &request)
+    }
+
+    #[test]
+    fn the_prefix_is_taken_off() {
+        assert_eq!(forwarded("/api", "/api/users"), "/users");
+        assert_eq!(forwarded("/api", "/api"), "/");
+        assert_eq!(forwarded("/api", "/api/"), "/");
+        assert_eq!(forwarded("/api/v1", "/api/v1/users/"), "/users/");
+        assert_eq!(forwarded("/", "/users"), "/users");
+    }
+
+    #[test]
+    fn the_rest_is_passed_on_as_sent() {
+        assert_eq!(forwarded("/api", "/api/a+b;c=d,e:f@g"), "/a+b;c=d,e:f@g");
+        assert_eq!(forwarded("/api", "/api/a%2Bb/caf%c3%a9"), "/a%2Bb/caf%C3%A9");
+        assert_eq!(forwarded("/api", "/api/a//b"), "/a//b");
+    }
+
+    #[test]
+    fn encoded_slashes_stay_encoded() {
+        assert_eq!(forwarded("/api", "/api/a%2Fb"), "/a%2Fb");
+        assert_eq!(forwarded("/api", "/api%2Fa/b"), "/a/b");
+        assert_eq!(forwarded("/api/a", "/api%2Fa%2fb"), "/b");
+    }
+
+    #[test]
+    fn dot_segments_are_removed() {
+        assert_eq!(forwarded("/api", "/api/./a/../b"), "/b");
+        assert_eq!(forwarded("/api", "/api/a/.."), "/");
+        assert_eq!(forwarded("/api", "/api/a/b/."), "/a/b/");
+        assert_eq!(forwarded("/api", "/x/../api/a"), "/a");
+        assert_eq!(forwarded("/api", "/x//../api/a"), "/a");
+        assert_eq!(forwarded("/api", "/api/a%2F..%2Fb"), "/b");
+        assert_eq!(forwarded("/api", "/api/%2E%2E/api/c"), "/c");
+    }
+
+    // How the body of a POST with `fields` would be delimited.
+    fn framing(fields: &str) -> Result<Framing> {
+        Proxy::framing(&parse(&format!("POST /api HTTP/1.1\r\nHost: localhost\r\n{}\r\n", fields)))
+    }
+
+    #[test]
+    fn bodies_are_delimited_one_way() {
+        assert!(matches!(framing(""), Ok(Framing::Empty)));
+        assert!(matches!(framing("Content-Length: 5\r\n"), Ok(Framing::Length(5))));
+        assert!(matches!(framing("Content-Length: 5\r\nContent-Length: 5\r\n"), Ok(Framing::Length(5))));
+        assert!(matches!(framing("Content-Length: 5, 5\r\n"), Ok(Framing::Length(5))));
+        assert!(matches!(framing("Transfer-Encoding: chunked\r\n"), Ok(Framing::Chunked)));
+        assert!(matches!(framing("Transfer-Encoding: gzip, chunked\r\n"), Err(Error::NotImplemented)));
+    }
+
+    #[test]
+    fn ambiguous_bodies_are_refused() {
+        assert!(matches!(framing("Content-Length: 5\r\nContent-Length: 6\r\n"), Err(Error::BadRequest)));
+        assert!(matches!(framing("Content-Length: 5, 6\r\n"), Err(Error::BadRequest)));
+        assert!(matches!(framing("Content-Length: +5\r\n"), Err(Error::BadRequest)));
+        assert!(matches!(framing("Transfer-Encoding: chunked\r\nContent-Length: 5\r\n"), Err(Error::BadRequest)));
+        assert!(matches!(framing("Content-Length: 5\r\nTransfer-Encoding: chunked\r\n"), Err(Error::BadRequest)));
+        assert!(matches!(framing("Transfer-Encoding: chunked\r\nTransfer-Encoding: chunked\r\n"), Err(Error::NotImplemented)));
+    }
+
+    #[test]
+    fn only_our_framing_goes_upstream() {
+        let mut proxy = Proxy::default();
+        proxy.add("/api=localhost:1").unwrap();
+        let request = parse("POST /api HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\nContent-Length: 5\r\n\r\n");
+        let route = proxy.route(&policy::normalize(&request.requested_path).unwrap()).unwrap();
+        let framing = Proxy::framing(&request).unwrap();
+        let head = String::from_utf8(Proxy::request_head(route, &request, "127.0.0.1".parse().unwrap(), &framing, false)).unwrap();
+        assert_eq!(head.matches("Content-Length").count(), 1);
+        assert!(head.contains("Content-Length: 5\r\n"));
+    }
+
+    #[test]
+    fn any_method_goes_upstream() {
+        let mut proxy = Proxy::default();
+        proxy.add("/api=localhost:1").unwrap();
+        for method in ["PATCH", "PROPFIND", "get"] {
+            let request = parse(&format!("{} /api/a HTTP/1.1\r\nHost: localhost\r\n\r\n", method));
+            let route = proxy.route(&policy::normalize(&request.requested_path).unwrap()).unwrap();
+            let head = Proxy::request_head(route, &request, "127.0.0.1".parse().unwrap(), &Framing::Empty, false);
+            assert!(head.starts_with(format!("{} /a HTTP/1.1\r\n", method).as_bytes()));
+        }
+    }
+}
@@ -0,0 +1,195 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_std::task;
+use futures::stream::StreamExt;
+
+use crate::error::{Error, Result};
+use crate::response::ByteStream;
+
+// Forget clients once there are this many, as long as their buckets have filled back up.
+const MAX_IDLE_CLIENTS: usize = 1024;
+// The slowest rate allowed, so that waits stay within reason.
+pub const MIN_RATE: f64 = 0.001;
+// No one is kept waiting longer than this, however deep in debt.
+const MAX_WAIT: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LimitBy {
+    Ip,
+    // The authenticated user, or the address for anonymous clients.
+    User,
+}
+
+impl LimitBy {
+    pub fn from_str(string: &str) -> Option<LimitBy> {
+        match string {
+            "ip" => Some(LimitBy::Ip),
+            "user" => Some(LimitBy::User),
+            _ => None,
+        }
+    }
+}
+
+/*
+* Fills at `rate` per second up to a second's worth, or one, whichever is more, which can be spent at
+* once. Spending more than there is runs into debt, which has to be waited out before anything more
+* is available.
+*/
+struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64) -> Self {
+        Self {
+            rate,
+            capacity: rate.max(1.0),
+            tokens: rate.max(1.0),
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        self.tokens = (self.tokens + now.duration_since(self.updated).as_secs_f64() * self.rate).min(self.capacity);
+        self.updated = now;
+    }
+
+    fn is_full(&mut self) -> bool {
+        self.refill();
+        self.tokens >= self.capacity
+    }
+
+    // Take `amount` if it's there, or say how long until it will be.
+    fn take(&mut self, amount: f64) -> std::result::Result<(), Duration> {
+        self.refill();
+        if self.tokens >= amount {
+            self.tokens -= amount;
+            Ok(())
+        } else {
+            Err(wait((amount - self.tokens) / self.rate))
+        }
+    }
+
+    // Take `amount` regardless, and say how long to wait for the debt to clear.
+    fn spend(&mut self, amount: f64) -> Duration {
+        self.refill();
+        self.tokens -= amount;
+        wait((-self.tokens).max(0.0) / self.rate)
+    }
+}
+
+// `seconds`, up to MAX_WAIT.
+fn wait(seconds: f64) -> Duration {
+    Duration::try_from_secs_f64(seconds).map_or(MAX_WAIT, |wait| wait.min(MAX_WAIT))
+}
+
+struct Client {
+    requests: Option<TokenBucket>,
+    // Shared with every response being sent to the client.
+    bytes: Option<Arc<Mutex<TokenBucket>>>,
+}
+
+// Per-client limits on requests and bytes per second.
+pub struct RateLimiter {
+    pub requests_per_second: Option<f64>,
+    pub bytes_per_second: Option<f64>,
+    pub limit_by: LimitBy,
+    clients: Mutex<HashMap<String, Client>>,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self {
+            requests_per_second: None,
+            bytes_per_second: None,
+            limit_by: LimitBy::Ip,
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl RateLimiter {
+    fn with_client<T>(&self, peer: IpAddr, user: Option<&str>, f: impl FnOnce(&mut Client) -> T) -> T {
+        let key = match (self.limit_by, user) {
+            (LimitBy::User, Some(user)) => format!("user {}", user),
+            _ => peer.to_string(),
+        };
+        let mut clients = self.clients.lock().unwrap();
+        if clients.len() >= MAX_IDLE_CLIENTS && !clients.contains_key(&key) {
+            clients.retain(|_, client| {
+                !(client.requests.as_mut().is_none_or(TokenBucket::is_full)
+                    && client.bytes.as_ref().is_none_or(|bytes| Arc::strong_count(bytes) == 1 && bytes.lock().unwrap().is_full()))
+            });
+        }
+        let client = clients.entry(key).or_insert_with(|| Client {
+            requests: self.requests_per_second.map(TokenBucket::new),
+            bytes: self.bytes_per_second.map(|rate| Arc::new(Mutex::new(TokenBucket::new(rate)))),
+        });
+        f(client)
+    }
+
+    /*
+    * RFC 6585, Page 3
+    * Count a request against the client, or refuse it with TooManyRequests and the number of
+    * seconds until it would be let through.
+    */
+    pub fn admit(&self, peer: IpAddr, user: Option<&str>) -> Result<()> {
+        if self.requests_per_second.is_none() {
+            return Ok(());
+        }
+        self.with_client(peer, user, |client| match client.requests.as_mut().map(|requests| requests.take(1.0)) {
+            Some(Err(wait)) => Err(Error::TooManyRequests(wait.as_secs_f64().ceil() as u64)),
+            _ => Ok(()),
+        })
+    }
+
+    // Slow `stream` down to the client's share of bandwidth.
+    pub fn throttle(&self, peer: IpAddr, user: Option<&str>, stream: ByteStream) -> ByteStream {
+        let bucket = match self.bytes_per_second {
+            Some(_) => self.with_client(peer, user, |client| client.bytes.clone()),
+            None => None,
+        };
+        let bucket = match bucket {
+            Some(bucket) => bucket,
+            None => return stream,
+        };
+        Box::new(Box::pin(stream.then(move |chunk| {
+            let bucket = bucket.clone();
+            async move {
+                if let Ok(bytes) = &chunk {
+                    let wait = bucket.lock().unwrap().spend(bytes.len() as f64);
+                    if !wait.is_zero() {
+                        task::sleep(wait).await;
+                    }
+                }
+                chunk
+            }
+        })))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn waits_are_capped() {
+        assert_eq!(wait(1.5), Duration::from_millis(1500));
+        assert_eq!(wait(1e300), MAX_WAIT);
+        assert_eq!(wait(f64::INFINITY), MAX_WAIT);
+    }
+
+    #[test]
+    fn deep_debt_does_not_overflow() {
+        let mut bucket = TokenBucket::new(MIN_RATE);
+        assert_eq!(bucket.take(1e300), Err(MAX_WAIT));
+        assert_eq!(bucket.spend(1e300), MAX_WAIT);
+    }
+}
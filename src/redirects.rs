@@ -0,0 +1,304 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::http::{HTTPRequest, percent_encode, percent_decode_segment};
+use crate::policy;
+use crate::response::{Redirect, RedirectKind};
+use crate::watched_file::WatchedFile;
+
+// Read from the root, like Netlify's.
+pub const REDIRECTS_FILE: &str = "_redirects";
+
+enum Segment {
+    Literal(String),
+    // :name matches any one segment.
+    Placeholder(String),
+    // A final * matches whatever is left, even nothing, as :splat.
+    Splat,
+}
+
+enum Condition {
+    // key=value
+    Equals(String),
+    // key=:name
+    Capture(String),
+}
+
+enum Action {
+    Redirect(RedirectKind),
+    // Serve the target in place of the requested path, as if it had been asked for.
+    Rewrite,
+}
+
+struct Rule {
+    from: Vec<Segment>,
+    conditions: Vec<(String, Condition)>,
+    to: String,
+    action: Action,
+}
+
+pub enum Outcome {
+    Redirect(Redirect),
+    Rewrite(HTTPRequest),
+}
+
+impl Rule {
+    /*
+    * FROM [KEY=VALUE...] TO [STATUS]
+    * where STATUS is 301 (the default), 302, 307, 308, or 200 for a rewrite.
+    */
+    fn parse(line: &str) -> Result<Rule, String> {
+        let mut fields: Vec<&str> = line.split_whitespace().collect();
+        let action = match fields.last().and_then(|status| status.parse::<u16>().ok()) {
+            Some(200) => Action::Rewrite,
+            Some(code) => Action::Redirect(RedirectKind::from_code(code).ok_or_else(|| format!("unsupported status {}", code))?),
+            None => Action::Redirect(RedirectKind::MovedPermanently),
+        };
+        if fields.last().is_some_and(|status| status.parse::<u16>().is_ok()) {
+            fields.pop();
+        }
+        let (from, to, conditions) = match fields[..] {
+            [from, ref conditions @ .., to] if from.starts_with('/') => (from, to, conditions),
+            _ => return Err("expected FROM [KEY=VALUE...] TO [STATUS]".to_owned()),
+        };
+        if matches!(action, Action::Rewrite) && !to.starts_with('/') {
+            return Err(format!("can't rewrite to {}, only to paths on this server", to));
+        }
+        let segments: Vec<&str> = from.split('/').filter(|segment| !segment.is_empty()).collect();
+        let mut pattern = Vec::new();
+        for (i, segment) in segments.iter().enumerate() {
+            pattern.push(match *segment {
+                "*" if i == segments.len() - 1 => Segment::Splat,
+                "*" => return Err("* has to come last".to_owned()),
+                segment => match segment.strip_prefix(':') {
+                    Some(name) => Segment::Placeholder(name.to_owned()),
+                    None => Segment::Literal(percent_decode_segment(segment).ok_or_else(|| format!("{} is not a valid segment", segment))?),
+                },
+            });
+        }
+        let mut parsed_conditions = Vec::new();
+        for condition in conditions {
+            let (key, value) = condition.split_once('=').ok_or_else(|| format!("expected KEY=VALUE, got {}", condition))?;
+            parsed_conditions.push((key.to_owned(), match value.strip_prefix(':') {
+                Some(name) => Condition::Capture(name.to_owned()),
+                None => Condition::Equals(value.to_owned()),
+            }));
+        }
+        Ok(Rule {
+            from: pattern,
+            conditions: parsed_conditions,
+            to: to.to_owned(),
+            action,
+        })
+    }
+
+    // The placeholders captured from the request, percent-encoded, if the rule matches it.
+    fn matches(&self, segments: &[String], request: &HTTPRequest) -> Option<HashMap<String, String>> {
+        let mut captures = HashMap::new();
+        let mut remaining = segments.iter();
+        for segment in &self.from {
+            match segment {
+                Segment::Literal(literal) => if remaining.next()? != literal {
+                    return None;
+                },
+                Segment::Placeholder(name) => {
+                    captures.insert(name.clone(), percent_encode(remaining.next()?));
+                },
+                Segment::Splat => {
+                    let rest: Vec<String> = remaining.by_ref().map(|segment| percent_encode(segment)).collect();
+                    captures.insert("splat".to_owned(), rest.join("/"));
+                },
+            }
+        }
+        if remaining.next().is_some() {
+            return None;
+        }
+        for (key, condition) in &self.conditions {
+            let value = request.query_parameter(key)?;
+            match condition {
+                Condition::Equals(expected) => if &value != expected {
+                    return None;
+                },
+                Condition::Capture(name) => {
+                    captures.insert(name.clone(), percent_encode(&value));
+                },
+            }
+        }
+        Some(captures)
+    }
+
+    // The target, with :name replaced by captured values. Unknown names are left alone.
+    fn target(&self, captures: &HashMap<String, String>) -> String {
+        let mut target = String::new();
+        let mut rest = self.to.as_str();
+        while let Some(start) = rest.find(':') {
+            target.push_str(&rest[..start]);
+            let after = &rest[start + 1..];
+            let length = after.find(|c: char| !c.is_ascii_alphanumeric() && c != '_').unwrap_or(after.len());
+            let name = &after[..length];
+            match captures.get(name) {
+                Some(value) if name.starts_with(|c: char| c.is_ascii_alphabetic()) => target.push_str(value),
+                _ => {
+                    target.push(':');
+                    target.push_str(name);
+                },
+            }
+            rest = &after[length..];
+        }
+        target.push_str(rest);
+        target
+    }
+}
+
+/*
+* Redirects and rewrites from a _redirects file in the root, one rule per line, read again whenever
+* it changes. The first rule to match the requested path, and its query conditions, applies.
+* Rewrites are not looked up again, so they can't loop.
+*/
+pub struct Redirects {
+    file: WatchedFile<Vec<Rule>>,
+}
+
+impl Default for Redirects {
+    fn default() -> Self {
+        Self {
+            file: WatchedFile::new(REDIRECTS_FILE, Self::parse),
+        }
+    }
+}
+
+impl Redirects {
+    // Invalid lines are reported and skipped, so one typo doesn't lose every rule.
+    fn parse(path: &Path, contents: &str) -> Vec<Rule> {
+        let mut rules = Vec::new();
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match Rule::parse(line) {
+                Ok(rule) => rules.push(rule),
+                Err(e) => eprintln!("{}: line {}: {}", path.display(), number + 1, e),
+            }
+        }
+        rules
+    }
+
+    pub async fn evaluate(&self, root: &Path, request: &HTTPRequest) -> Option<Outcome> {
+        let rules = self.file.get(root).await?;
+        let segments: Vec<String> = policy::normalize(&request.requested_path).ok()?
+            .iter()
+            .filter_map(|segment| segment.to_str().map(str::to_owned))
+            .collect();
+        let (rule, captures) = rules.iter().find_map(|rule| rule.matches(&segments, request).map(|captures| (rule, captures)))?;
+        let mut target = rule.target(&captures);
+        // Without conditions of its own, a rule passes the query along.
+        if let Some(query) = request.query.as_ref().filter(|_| rule.conditions.is_empty() && !target.contains('?')) {
+            target.push('?');
+            target.push_str(query);
+        }
+        match rule.action {
+            Action::Redirect(kind) => Some(Outcome::Redirect(Redirect { kind, location: target, headers: Vec::new() })),
+            Action::Rewrite => {
+                let (path, query) = match target.split_once('?') {
+                    Some((path, query)) => (path, Some(query.to_owned())),
+                    None => (target.as_str(), None),
+                };
+                // A target that doesn't decode can only lead to a 404.
+                let requested_path = path.split('/').skip(1).map(|segment| percent_decode_segment(segment).unwrap_or_default()).collect();
+                let raw_path = path.split('/').skip(1).map(str::to_owned).collect();
+                Some(Outcome::Rewrite(HTTPRequest { requested_path, raw_path, query, ..request.clone() }))
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::io::BufReader;
+    use async_std::task;
+
+    use super::*;
+    use crate::http::Parser;
+    use crate::peekable_bufreader::PeekableBufReader;
+
+    // Where the first of `rules` to match a request for `target` points, if any does.
+    fn resolve(rules: &str, target: &str) -> Option<String> {
+        let rules = Redirects::parse(Path::new(REDIRECTS_FILE), rules);
+        let message = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", target);
+        let mut parser = Parser::new(PeekableBufReader::new(BufReader::new(message.as_bytes())));
+        let request = task::block_on(parser.parse()).expect("the request should parse");
+        let segments: Vec<String> = policy::normalize(&request.requested_path).unwrap()
+            .iter()
+            .map(|segment| segment.to_str().unwrap().to_owned())
+            .collect();
+        rules.iter().find_map(|rule| rule.matches(&segments, &request).map(|captures| rule.target(&captures)))
+    }
+
+    #[test]
+    fn literal_paths_match_exactly() {
+        assert_eq!(resolve("/old /new", "/old").as_deref(), Some("/new"));
+        assert_eq!(resolve("/old /new", "/old/").as_deref(), Some("/new"));
+        assert_eq!(resolve("/old /new", "/old/page"), None);
+        assert_eq!(resolve("/old /new", "/older"), None);
+        assert_eq!(resolve("/caf%C3%A9 /cafe", "/caf%c3%a9").as_deref(), Some("/cafe"));
+    }
+
+    #[test]
+    fn placeholders_are_substituted() {
+        let rules = "/blog/:year/:slug /posts/:year-:slug";
+        assert_eq!(resolve(rules, "/blog/2024/hello").as_deref(), Some("/posts/2024-hello"));
+        assert_eq!(resolve(rules, "/blog/2024/a%20b").as_deref(), Some("/posts/2024-a%20b"));
+        assert_eq!(resolve(rules, "/blog/2024"), None);
+        assert_eq!(resolve("/a/:name /b/:other", "/a/x").as_deref(), Some("/b/:other"));
+        assert_eq!(resolve("/a/:name http://example.com:8080/:name", "/a/x").as_deref(), Some("http://example.com:8080/x"));
+    }
+
+    #[test]
+    fn the_splat_takes_the_rest() {
+        let rules = "/docs/* /manual/:splat";
+        assert_eq!(resolve(rules, "/docs/a/b.html").as_deref(), Some("/manual/a/b.html"));
+        assert_eq!(resolve(rules, "/docs").as_deref(), Some("/manual/"));
+        assert_eq!(resolve(rules, "/documents"), None);
+    }
+
+    #[test]
+    fn a_splat_at_the_root_matches_everything() {
+        let rules = "/* https://example.com/:splat 302";
+        assert_eq!(resolve(rules, "/").as_deref(), Some("https://example.com/"));
+        assert_eq!(resolve(rules, "/a/b").as_deref(), Some("https://example.com/a/b"));
+    }
+
+    #[test]
+    fn query_conditions_have_to_hold() {
+        let rules = "/search q=:term /find/:term\n/page id=1 /first";
+        assert_eq!(resolve(rules, "/search?q=cats").as_deref(), Some("/find/cats"));
+        assert_eq!(resolve(rules, "/search"), None);
+        assert_eq!(resolve(rules, "/page?id=1").as_deref(), Some("/first"));
+        assert_eq!(resolve(rules, "/page?id=2"), None);
+    }
+
+    #[test]
+    fn the_first_match_wins() {
+        let rules = "/a /first\n/:any /second";
+        assert_eq!(resolve(rules, "/a").as_deref(), Some("/first"));
+        assert_eq!(resolve(rules, "/b").as_deref(), Some("/second"));
+    }
+
+    #[test]
+    fn statuses_are_parsed() {
+        assert!(matches!(Rule::parse("/a /b").unwrap().action, Action::Redirect(RedirectKind::MovedPermanently)));
+        assert!(matches!(Rule::parse("/a /b 302").unwrap().action, Action::Redirect(RedirectKind::Found)));
+        assert!(matches!(Rule::parse("/a /b 200").unwrap().action, Action::Rewrite));
+        assert!(Rule::parse("/a /b 404").is_err());
+        assert!(Rule::parse("/a http://example.com/ 200").is_err());
+    }
+
+    #[test]
+    fn malformed_lines_are_skipped() {
+        let rules = "# comment\n\n/a\nnot-a-path /b\n/* /b/* /c\n/d key /e\n/f /g 418\n/ok /fine\n";
+        assert_eq!(Redirects::parse(Path::new(REDIRECTS_FILE), rules).len(), 1);
+        assert_eq!(resolve(rules, "/ok").as_deref(), Some("/fine"));
+        assert_eq!(resolve(rules, "/a"), None);
+    }
+}
@@ -1,55 +1,289 @@
+use std::io;
+use std::path::Path;
+
+use async_std::fs;
 use futures::prelude::*;
 
+use crate::html;
+use crate::template;
+use crate::websocket;
+
+// A failed chunk means the body can't be completed; the connection should be dropped.
+pub type ByteStream = Box<dyn Stream<Item = io::Result<Vec<u8>>> + Unpin + Send + Sync>;
+
 pub trait Response: Send + Sync {
-    fn response_bytes(self: Box<Self>) -> Box<dyn Stream<Item = Vec<u8>> + Unpin + Send>;
+    fn status_code(&self) -> u16;
+    // Header fields to send, other than those implied by the response itself.
+    fn headers_mut(&mut self) -> &mut Vec<(String, String)>;
+    // Whether the body is a page of ours, like a listing, rather than a file being served.
+    fn is_generated(&self) -> bool {
+        false
+    }
+    // What takes over the connection once the response is sent, if it switches protocols.
+    fn upgrade(&self) -> Option<websocket::Handler> {
+        None
+    }
+    fn response_bytes(self: Box<Self>) -> ByteStream;
+}
+
+// Serialize a status line and header fields, up to and including the empty line.
+pub fn head(status: &str, headers: &[(String, String)]) -> Vec<u8> {
+    let mut head = format!("HTTP/1.1 {}\r\n", status);
+    for (name, value) in headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+    head.into_bytes()
+}
+
+// Add a header field, appending to an existing one of the same name for the list-valued Vary.
+pub fn add_header(headers: &mut Vec<(String, String)>, name: String, value: String) {
+    if name.eq_ignore_ascii_case("vary") {
+        if let Some((_, existing)) = headers.iter_mut().find(|(existing, _)| existing.eq_ignore_ascii_case("vary")) {
+            existing.push_str(", ");
+            existing.push_str(&value);
+            return;
+        }
+    }
+    headers.push((name, value));
 }
 
 pub struct Ok {
-    pub file_stream: Box<dyn Stream<Item = Vec<u8>> + Unpin + Send + Sync>,
+    pub headers: Vec<(String, String)>,
+    pub file_stream: ByteStream,
+    pub generated: bool,
 }
 
 impl Response for Ok {
-    fn response_bytes(self: Box<Self>) -> Box<dyn Stream<Item = Vec<u8>> + Unpin + Send> {
-        Box::new(stream::iter(vec![Vec::from(b"HTTP/1.1 200 OK\r\n\r\n" as &[u8])].into_iter().map(|entry| entry.to_owned())).chain(self.file_stream))
+    fn status_code(&self) -> u16 {
+        200
     }
-}
 
-pub struct BadRequest {
+    fn headers_mut(&mut self) -> &mut Vec<(String, String)> {
+        &mut self.headers
+    }
 
-}
+    fn is_generated(&self) -> bool {
+        self.generated
+    }
 
-impl Response for BadRequest {
-    fn response_bytes(self: Box<Self>) -> Box<dyn Stream<Item = Vec<u8>> + Unpin + Send> {
-        Box::new(stream::iter(vec![Vec::from(b"HTTP/1.1 400 Bad Request\r\n\r\n" as &[u8]), include_bytes!("../res/400.html").to_vec()].into_iter().map(|entry| entry.to_owned())))
+    fn response_bytes(self: Box<Self>) -> ByteStream {
+        Box::new(stream::iter(vec![Result::Ok(head("200 OK", &self.headers))]).chain(self.file_stream))
     }
 }
 
-pub struct NotFound {
+/*
+* RFC 7231, Page 54; RFC 7538
+* 301 and 308 are permanent, 302 and 307 temporary; 307 and 308 also forbid changing the method.
+*/
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RedirectKind {
+    MovedPermanently,
+    Found,
+    TemporaryRedirect,
+    PermanentRedirect,
+}
+
+impl RedirectKind {
+    pub fn from_code(code: u16) -> Option<RedirectKind> {
+        match code {
+            301 => Some(RedirectKind::MovedPermanently),
+            302 => Some(RedirectKind::Found),
+            307 => Some(RedirectKind::TemporaryRedirect),
+            308 => Some(RedirectKind::PermanentRedirect),
+            _ => None,
+        }
+    }
+
+    pub fn code(&self) -> u16 {
+        match self {
+            RedirectKind::MovedPermanently => 301,
+            RedirectKind::Found => 302,
+            RedirectKind::TemporaryRedirect => 307,
+            RedirectKind::PermanentRedirect => 308,
+        }
+    }
+
+    pub fn reason(&self) -> &'static str {
+        match self {
+            RedirectKind::MovedPermanently => "Moved Permanently",
+            RedirectKind::Found => "Found",
+            RedirectKind::TemporaryRedirect => "Temporary Redirect",
+            RedirectKind::PermanentRedirect => "Permanent Redirect",
+        }
+    }
+}
 
+pub struct Redirect {
+    pub kind: RedirectKind,
+    pub location: String,
+    pub headers: Vec<(String, String)>,
 }
 
-impl Response for NotFound {
-    fn response_bytes(self: Box<Self>) -> Box<dyn Stream<Item = Vec<u8>> + Unpin + Send> {
-        Box::new(stream::iter(vec![Vec::from(b"HTTP/1.1 404 Not Found\r\n\r\n" as &[u8]), include_bytes!("../res/404.html").to_vec()].into_iter().map(|entry| entry.to_owned())))
+impl Response for Redirect {
+    fn status_code(&self) -> u16 {
+        self.kind.code()
+    }
+
+    fn headers_mut(&mut self) -> &mut Vec<(String, String)> {
+        &mut self.headers
     }
+
+    fn response_bytes(self: Box<Self>) -> ByteStream {
+        let Redirect { kind, location, mut headers } = *self;
+        headers.insert(0, ("Location".to_owned(), location));
+        let status = format!("{} {}", kind.code(), kind.reason());
+        Box::new(stream::iter(vec![Result::Ok(head(&status, &headers))]))
+    }
+}
+
+// A successful response without a body, e.g. to a CORS preflight request.
+pub struct NoContent {
+    pub headers: Vec<(String, String)>,
 }
 
-pub struct NotImplemented {
+impl Response for NoContent {
+    fn status_code(&self) -> u16 {
+        204
+    }
+
+    fn headers_mut(&mut self) -> &mut Vec<(String, String)> {
+        &mut self.headers
+    }
+
+    fn response_bytes(self: Box<Self>) -> ByteStream {
+        Box::new(stream::iter(vec![Result::Ok(head("204 No Content", &self.headers))]))
+    }
+}
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Status {
+    BadRequest,
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    URITooLong,
+    UpgradeRequired,
+    TooManyRequests,
+    RequestHeaderFieldsTooLarge,
+    InternalServerError,
+    NotImplemented,
+    BadGateway,
+    GatewayTimeout,
+    HTTPVersionNotSupported,
 }
 
-impl Response for NotImplemented {
-    fn response_bytes(self: Box<Self>) -> Box<dyn Stream<Item = Vec<u8>> + Unpin + Send> {
-        Box::new(stream::iter(vec![Vec::from(b"HTTP/1.1 501 Not Implemented\r\n\r\n" as &[u8]), include_bytes!("../res/501.html").to_vec()].into_iter().map(|entry| entry.to_owned())))
+impl Status {
+    pub fn code(&self) -> u16 {
+        match self {
+            Status::BadRequest => 400,
+            Status::Unauthorized => 401,
+            Status::Forbidden => 403,
+            Status::NotFound => 404,
+            Status::URITooLong => 414,
+            Status::UpgradeRequired => 426,
+            Status::TooManyRequests => 429,
+            Status::RequestHeaderFieldsTooLarge => 431,
+            Status::InternalServerError => 500,
+            Status::NotImplemented => 501,
+            Status::BadGateway => 502,
+            Status::GatewayTimeout => 504,
+            Status::HTTPVersionNotSupported => 505,
+        }
+    }
+
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Status::BadRequest => "Bad Request",
+            Status::Unauthorized => "Unauthorized",
+            Status::Forbidden => "Forbidden",
+            Status::NotFound => "Not Found",
+            Status::URITooLong => "URI Too Long",
+            Status::UpgradeRequired => "Upgrade Required",
+            Status::TooManyRequests => "Too Many Requests",
+            Status::RequestHeaderFieldsTooLarge => "Request Header Fields Too Large",
+            Status::InternalServerError => "Internal Server Error",
+            Status::NotImplemented => "Not Implemented",
+            Status::BadGateway => "Bad Gateway",
+            Status::GatewayTimeout => "Gateway Timeout",
+            Status::HTTPVersionNotSupported => "HTTP Version Not Supported",
+        }
     }
+
+    fn default_page(&self) -> &'static [u8] {
+        match self {
+            Status::BadRequest => include_bytes!("../res/400.html"),
+            Status::Unauthorized => include_bytes!("../res/401.html"),
+            Status::Forbidden => include_bytes!("../res/403.html"),
+            Status::NotFound => include_bytes!("../res/404.html"),
+            Status::URITooLong => include_bytes!("../res/414.html"),
+            Status::UpgradeRequired => include_bytes!("../res/426.html"),
+            Status::TooManyRequests => include_bytes!("../res/429.html"),
+            Status::RequestHeaderFieldsTooLarge => include_bytes!("../res/431.html"),
+            Status::InternalServerError => include_bytes!("../res/500.html"),
+            Status::NotImplemented => include_bytes!("../res/501.html"),
+            Status::BadGateway => include_bytes!("../res/502.html"),
+            Status::GatewayTimeout => include_bytes!("../res/504.html"),
+            Status::HTTPVersionNotSupported => include_bytes!("../res/505.html"),
+        }
+    }
+}
+
+pub struct ErrorPage {
+    pub status: Status,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
 }
 
-pub struct InternalServerError {
+impl ErrorPage {
+    // The built-in page for `status`.
+    pub fn new(status: Status) -> Self {
+        Self {
+            status,
+            headers: vec![("Content-Type".to_owned(), "text/html; charset=utf-8".to_owned())],
+            body: status.default_page().to_vec(),
+        }
+    }
 
+    /*
+    * A user-supplied page for `status` from `directory`. For a 404, that's the first of 404.html,
+    * 4xx.html and error.html to exist. {status}, {reason} and {path} are filled in.
+    */
+    pub async fn from_directory(status: Status, requested_path: &str, directory: &Path) -> Option<Self> {
+        let code = status.code().to_string();
+        let candidates = [format!("{}.html", code), format!("{}xx.html", &code[..1]), "error.html".to_owned()];
+        for candidate in &candidates {
+            if let Ok(page) = fs::read_to_string(directory.join(candidate)).await {
+                let body = template::fill(&page, &[
+                    ("status", &code),
+                    ("reason", status.reason()),
+                    ("path", &html::escape(requested_path)),
+                ]);
+                return Some(Self {
+                    body: body.into_bytes(),
+                    ..Self::new(status)
+                });
+            }
+        }
+        None
+    }
 }
 
-impl Response for InternalServerError {
-    fn response_bytes(self: Box<Self>) -> Box<dyn Stream<Item = Vec<u8>> + Unpin + Send> {
-        Box::new(stream::iter(vec![Vec::from(b"HTTP/1.1 500 InternalServerError\r\n\r\n" as &[u8]), include_bytes!("../res/500.html").to_vec()].into_iter().map(|entry| entry.to_owned())))
+impl Response for ErrorPage {
+    fn status_code(&self) -> u16 {
+        self.status.code()
+    }
+
+    fn headers_mut(&mut self) -> &mut Vec<(String, String)> {
+        &mut self.headers
+    }
+
+    fn is_generated(&self) -> bool {
+        true
+    }
+
+    fn response_bytes(self: Box<Self>) -> ByteStream {
+        let status = format!("{} {}", self.status.code(), self.status.reason());
+        Box::new(stream::iter(vec![head(&status, &self.headers), self.body].into_iter().map(Result::Ok)))
     }
 }
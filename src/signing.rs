@@ -0,0 +1,119 @@
+use std::fs;
+use std::path::Path;
+
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::http::percent_encode;
+
+pub const USAGE: &str = "\
+Usage: tiny-serve sign --signing-key FILE [--expires DURATION] PATH
+
+Prints a link to PATH that is valid without credentials until it expires.
+
+Options:
+    --signing-key FILE  The key the server was started with.
+    --expires DURATION  How long the link is valid for, in seconds or with a
+                        suffix of s, m, h or d (default: 1d).
+";
+
+/*
+* Time-limited links to single files: ?expires=UNIX-TIME&sig=HEX, where the signature is
+* HMAC-SHA256 over the expiry time and the decoded path, so neither can be changed.
+*/
+pub struct SigningKey {
+    key: Vec<u8>,
+}
+
+impl SigningKey {
+    // Read from `path`, ignoring a trailing line break.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let mut key = fs::read(path).map_err(|e| e.to_string())?;
+        while key.last().is_some_and(|byte| *byte == b'\n' || *byte == b'\r') {
+            key.pop();
+        }
+        if key.is_empty() {
+            return Err("the key is empty".to_owned());
+        }
+        Ok(Self { key })
+    }
+
+    fn mac(&self, path: &str, expires: i64) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC accepts keys of any length");
+        mac.update(format!("{}\n{}", expires, path).as_bytes());
+        mac
+    }
+
+    // The query string that makes `path` available until `expires`.
+    pub fn sign(&self, path: &str, expires: i64) -> String {
+        let signature: String = self.mac(path, expires).finalize().into_bytes().iter().map(|byte| format!("{:02x}", byte)).collect();
+        format!("expires={}&sig={}", expires, signature)
+    }
+
+    // Whether `signature` is a valid, unexpired signature of `path`.
+    pub fn verify(&self, path: &str, expires: &str, signature: &str) -> bool {
+        let expires = match expires.parse::<i64>() {
+            Ok(expires) if expires >= Utc::now().timestamp() => expires,
+            _ => return false,
+        };
+        match decode_hex(signature) {
+            Some(signature) => self.mac(path, expires).verify_slice(&signature).is_ok(),
+            None => false,
+        }
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()).collect()
+}
+
+// A duration in seconds, with an optional unit suffix.
+fn parse_duration(duration: &str) -> Option<i64> {
+    let (number, unit) = match duration.char_indices().last()? {
+        (i, 's') => (&duration[..i], 1),
+        (i, 'm') => (&duration[..i], 60),
+        (i, 'h') => (&duration[..i], 60 * 60),
+        (i, 'd') => (&duration[..i], 24 * 60 * 60),
+        _ => (duration, 1),
+    };
+    number.parse::<i64>().ok().filter(|number| *number > 0)?.checked_mul(unit)
+}
+
+// The `sign` subcommand: print a signed link for a path.
+pub fn run<I>(mut args: I) -> Result<(), String>
+    where I: Iterator<Item = String> {
+    let mut key = None;
+    let mut lifetime = 24 * 60 * 60;
+    let mut path = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--signing-key" => {
+                let file = args.next().ok_or("--signing-key requires a value")?;
+                key = Some(SigningKey::load(Path::new(&file)).map_err(|e| format!("--signing-key: {}: {}", file, e))?);
+            },
+            "--expires" => {
+                let duration = args.next().ok_or("--expires requires a value")?;
+                lifetime = parse_duration(&duration).ok_or_else(|| format!("--expires: invalid duration {}", duration))?;
+            },
+            option if option.starts_with('-') => return Err(format!("unknown option {}", option)),
+            _ if path.is_some() => return Err("only one path may be signed at a time".to_owned()),
+            _ => path = Some(arg),
+        }
+    }
+    let key = key.ok_or("--signing-key is required")?;
+    let path = path.ok_or("a path to sign is required")?;
+    let segments: Vec<&str> = path.split('/').filter(|segment| !segment.is_empty() && *segment != ".").collect();
+    if segments.contains(&"..") {
+        return Err(format!("{} must not contain ..", path));
+    }
+    let mut encoded: String = segments.iter().map(|segment| format!("/{}", percent_encode(segment))).collect();
+    if encoded.is_empty() {
+        encoded.push('/');
+    }
+    println!("{}?{}", encoded, key.sign(&format!("/{}", segments.join("/")), Utc::now().timestamp() + lifetime));
+    Ok(())
+}
@@ -0,0 +1,44 @@
+/*
+* Replace each {name} in `template` with its value. Braces around anything that isn't one of the
+* given names are left alone, so stylesheets and scripts survive untouched.
+*/
+pub fn fill(template: &str, values: &[(&str, &str)]) -> String {
+    let mut filled = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        filled.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let value = rest.find('}').and_then(|end| {
+            let name = &rest[1..end];
+            values.iter().find(|(key, _)| *key == name).map(|(_, value)| (end, value))
+        });
+        match value {
+            Some((end, value)) => {
+                filled.push_str(value);
+                rest = &rest[end + 1..];
+            },
+            None => {
+                filled.push('{');
+                rest = &rest[1..];
+            },
+        }
+    }
+    filled.push_str(rest);
+    filled
+}
+
+// The names of everything in `template` that looks like a placeholder: {lowercase_name}.
+pub fn placeholders(template: &str) -> Vec<&str> {
+    let mut names = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        rest = &rest[start + 1..];
+        if let Some(end) = rest.find('}') {
+            let name = &rest[..end];
+            if !name.is_empty() && name.bytes().all(|byte| byte.is_ascii_lowercase() || byte.is_ascii_digit() || byte == b'_') {
+                names.push(name);
+            }
+        }
+    }
+    names
+}
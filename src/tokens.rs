@@ -0,0 +1,95 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
+use crate::policy;
+
+pub struct Token {
+    // Logged in place of a user name.
+    pub name: String,
+    // Of the token itself, so lookups take the same time whatever was presented.
+    digest: [u8; 32],
+    read: bool,
+    write: bool,
+    // Relative to the root, like normalized request paths.
+    scopes: Vec<PathBuf>,
+}
+
+impl Token {
+    // Whether the token grants reading, or writing, `path`, relative to the root.
+    pub fn permits(&self, path: &Path, write: bool) -> bool {
+        let right = if write { self.write } else { self.read };
+        right && self.scopes.iter().any(|scope| path.starts_with(scope))
+    }
+}
+
+/*
+* Bearer tokens for automated clients, one per line:
+*     NAME TOKEN RIGHTS PREFIX...
+* where RIGHTS is read, write or read,write and each PREFIX is a path the token may be used under.
+* Blank lines and lines starting with '#' are skipped.
+*/
+#[derive(Default)]
+pub struct Tokens {
+    tokens: Vec<Token>,
+}
+
+impl Tokens {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let contents = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let mut tokens = Vec::new();
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let (name, token, rights, prefixes) = match fields[..] {
+                [name, token, rights, ref prefixes @ ..] if !prefixes.is_empty() => (name, token, rights, prefixes),
+                _ => return Err(format!("line {}: expected NAME TOKEN RIGHTS PREFIX...", number + 1)),
+            };
+            let (mut read, mut write) = (false, false);
+            for right in rights.split(',') {
+                match right {
+                    "read" => read = true,
+                    "write" => write = true,
+                    _ => return Err(format!("line {}: unknown right {}", number + 1, right)),
+                }
+            }
+            let mut scopes = Vec::new();
+            for prefix in prefixes {
+                let segments: Vec<String> = prefix.split('/').map(str::to_owned).collect();
+                scopes.push(policy::normalize(&segments).map_err(|_| format!("line {}: {} leads outside the root", number + 1, prefix))?);
+            }
+            tokens.push(Token {
+                name: name.to_owned(),
+                digest: Sha256::digest(token.as_bytes()).into(),
+                read,
+                write,
+                scopes,
+            });
+        }
+        Ok(Self { tokens })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    /*
+    * Every token's digest is compared in full, without stopping at the first difference or the
+    * first match, so how long this takes says nothing about how close a guess came.
+    */
+    pub fn get(&self, token: &str) -> Option<&Token> {
+        let digest: [u8; 32] = Sha256::digest(token.as_bytes()).into();
+        let mut found = None;
+        for candidate in &self.tokens {
+            let difference = candidate.digest.iter().zip(&digest).fold(0, |difference, (a, b)| difference | (a ^ b));
+            if difference == 0 {
+                found = Some(candidate);
+            }
+        }
+        found
+    }
+}
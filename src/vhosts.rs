@@ -0,0 +1,106 @@
+use std::env;
+use std::path::{Path, PathBuf};
+
+use crate::error::{Error, Result};
+use crate::http::{HTTPRequest, Version};
+
+struct Host {
+    // Lowercase, without a port; "*.example.com" stands for any subdomain of example.com.
+    name: String,
+    root: PathBuf,
+}
+
+impl Host {
+    // How well `host` matches: exact names beat wildcards, and longer wildcards beat shorter ones.
+    fn specificity(&self, host: &str) -> Option<usize> {
+        match self.name.strip_prefix('*') {
+            Some(suffix) => host.ends_with(suffix).then_some(suffix.len()),
+            None => (self.name == host).then_some(usize::MAX),
+        }
+    }
+}
+
+/*
+* Several sites from one process, told apart by the Host header. A request for a host we don't
+* know is served from the default host's root if there is one, or else the current directory.
+*/
+#[derive(Default)]
+pub struct VirtualHosts {
+    hosts: Vec<Host>,
+    default: Option<String>,
+}
+
+impl VirtualHosts {
+    // Add a host given as NAME=DIRECTORY.
+    pub fn add(&mut self, spec: &str) -> std::result::Result<(), String> {
+        let (name, directory) = spec.split_once('=').ok_or_else(|| format!("expected HOST=DIR, got {}", spec))?;
+        let name = name.to_ascii_lowercase();
+        if name.is_empty() || name.strip_prefix("*.").unwrap_or(&name).contains('*') {
+            return Err(format!("{} is not a host name or *.domain", name));
+        }
+        let root = PathBuf::from(directory).canonicalize().map_err(|e| format!("{}: {}", directory, e))?;
+        if !root.is_dir() {
+            return Err(format!("{} is not a directory", directory));
+        }
+        self.hosts.push(Host { name, root });
+        Ok(())
+    }
+
+    pub fn set_default(&mut self, name: &str) -> std::result::Result<(), String> {
+        let name = name.to_ascii_lowercase();
+        if !self.hosts.iter().any(|host| host.name == name) {
+            return Err(format!("{} is not one of the --vhost hosts", name));
+        }
+        self.default = Some(name);
+        Ok(())
+    }
+
+    // The roots of all the hosts.
+    pub fn roots(&self) -> impl Iterator<Item = &Path> {
+        self.hosts.iter().map(|host| host.root.as_path())
+    }
+
+    // Every root a request might be served from, including the current directory unless unknown hosts go to the default.
+    pub fn served_roots(&self) -> Vec<PathBuf> {
+        let mut roots: Vec<PathBuf> = self.roots().map(Path::to_path_buf).collect();
+        if self.default.is_none() {
+            roots.push(PathBuf::from("."));
+        }
+        roots
+    }
+
+    /*
+    * RFC 7230, Page 44
+    * The canonical root to serve `request` from. An HTTP/1.1 request without a Host header field,
+    * or with an invalid one, gets a 400; HTTP/1.0 clients may leave it out.
+    */
+    pub fn root(&self, request: &HTTPRequest) -> Result<PathBuf> {
+        let host = match request.header("host") {
+            Some(host) => Some(Self::hostname(host).ok_or(Error::BadRequest)?),
+            None if matches!(request.version, Version::Http1_1) => return Err(Error::BadRequest),
+            None => None,
+        };
+        let matched = host.and_then(|host| {
+            self.hosts.iter().filter_map(|candidate| candidate.specificity(&host).map(|specificity| (specificity, candidate))).max_by_key(|(specificity, _)| *specificity)
+        });
+        let default = || self.default.as_ref().and_then(|name| self.hosts.iter().find(|host| &host.name == name));
+        match matched.map(|(_, host)| host).or_else(default) {
+            Some(host) => Ok(host.root.clone()),
+            None => Ok(env::current_dir()?.canonicalize()?),
+        }
+    }
+
+    // uri-host [ ":" port ], lowercased and without the port. Bracketed IPv6 literals are kept whole.
+    fn hostname(host: &str) -> Option<String> {
+        let host = host.trim();
+        let name = if host.starts_with('[') {
+            &host[..=host.find(']')?]
+        } else {
+            host.split(':').next()?
+        };
+        if name.is_empty() || name.contains(|c: char| c == ',' || c.is_whitespace() || c == '/') {
+            return None;
+        }
+        Some(name.to_ascii_lowercase())
+    }
+}
@@ -0,0 +1,57 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use async_std::fs;
+
+struct Loaded<T> {
+    // None if the filesystem doesn't keep modification times, in which case it's always read.
+    modified: Option<SystemTime>,
+    value: Arc<T>,
+}
+
+// A configuration file in each root, parsed when first needed and again whenever it changes.
+pub struct WatchedFile<T> {
+    name: &'static str,
+    // Given the file's path, for messages.
+    parse: fn(&Path, &str) -> T,
+    // By root.
+    loaded: Mutex<HashMap<PathBuf, Loaded<T>>>,
+}
+
+impl<T> WatchedFile<T> {
+    pub fn new(name: &'static str, parse: fn(&Path, &str) -> T) -> Self {
+        Self {
+            name,
+            parse,
+            loaded: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // The parsed contents of the file in `root`, or None if there's no such file.
+    pub async fn get(&self, root: &Path) -> Option<Arc<T>> {
+        let path = root.join(self.name);
+        let modified = match fs::metadata(&path).await {
+            Ok(metadata) => metadata.modified().ok(),
+            Err(_) => {
+                self.loaded.lock().unwrap().remove(root);
+                return None;
+            },
+        };
+        if let Some(current) = self.loaded.lock().unwrap().get(root) {
+            if current.modified.is_some() && current.modified == modified {
+                return Some(current.value.clone());
+            }
+        }
+        // Read without holding the lock, so other requests aren't kept waiting on the disk. If two
+        // read it at once, the last one in wins, which is just as current.
+        let contents = fs::read_to_string(&path).await.unwrap_or_else(|e| {
+            eprintln!("{}: {}", path.display(), e);
+            String::new()
+        });
+        let value = Arc::new((self.parse)(&path, &contents));
+        self.loaded.lock().unwrap().insert(root.to_path_buf(), Loaded { modified, value: value.clone() });
+        Some(value)
+    }
+}
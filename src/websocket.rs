@@ -0,0 +1,395 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use async_std::prelude::*;
+use async_std::net::TcpStream;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use futures::future::BoxFuture;
+use sha1::{Digest, Sha1};
+
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::http::{HTTPRequest, Version};
+use crate::peekable_bufreader::PeekableBufReader;
+use crate::response::{self, ByteStream, Response};
+
+/* RFC 6455, Page 24 */
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const VERSION: &str = "13";
+
+// Bigger messages are refused with 1009, so one client can't eat all our memory.
+const MAX_MESSAGE_LENGTH: u64 = 16 << 20;
+
+/* RFC 6455, Page 29 */
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+/* RFC 6455, Page 45 */
+pub const CLOSE_GOING_AWAY: u16 = 1001;
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+const CLOSE_INVALID_DATA: u16 = 1007;
+const CLOSE_TOO_BIG: u16 = 1009;
+
+/*
+* What a route does with a WebSocket once the handshake is done: it gets the connection, the
+* configuration and the root the request was for, and runs until it's done with the client.
+*/
+pub type Handler = fn(WebSocket, Arc<Config>, PathBuf) -> BoxFuture<'static, io::Result<()>>;
+
+// The paths that accept WebSockets, and what to do with them.
+#[derive(Default)]
+pub struct Routes {
+    handlers: HashMap<String, Handler>,
+}
+
+impl Routes {
+    pub fn add(&mut self, path: &str, handler: Handler) {
+        self.handlers.insert(path.to_owned(), handler);
+    }
+
+    pub fn get(&self, path: &str) -> Option<Handler> {
+        self.handlers.get(path).copied()
+    }
+}
+
+// Whether `request` asks to switch to WebSocket, as opposed to an upgrade we don't know.
+pub fn is_upgrade(request: &HTTPRequest) -> bool {
+    request.header("upgrade").is_some_and(|upgrade| has_token(upgrade, "websocket"))
+}
+
+/*
+* RFC 6455, Page 21
+* Check the opening handshake and agree to it. It has to be an HTTP/1.1 GET asking to upgrade the
+* connection, with a 16-byte key and version 13; other versions get a 426 naming ours.
+*/
+pub fn handshake(request: &HTTPRequest, handler: Handler) -> Result<SwitchingProtocols> {
+    if !matches!(request.version, Version::Http1_1) || !request.header("connection").is_some_and(|connection| has_token(connection, "upgrade")) {
+        return Err(Error::BadRequest);
+    }
+    if request.header("sec-websocket-version").map(str::trim) != Some(VERSION) {
+        return Err(Error::UpgradeRequired(vec![("Sec-WebSocket-Version".to_owned(), VERSION.to_owned())]));
+    }
+    let key = request.header("sec-websocket-key").map(str::trim).ok_or(Error::BadRequest)?;
+    if STANDARD.decode(key).map_or(true, |nonce| nonce.len() != 16) {
+        return Err(Error::BadRequest);
+    }
+    Ok(SwitchingProtocols {
+        headers: vec![
+            ("Upgrade".to_owned(), "websocket".to_owned()),
+            ("Connection".to_owned(), "Upgrade".to_owned()),
+            ("Sec-WebSocket-Accept".to_owned(), accept_key(key)),
+        ],
+        handler,
+    })
+}
+
+// The Sec-WebSocket-Accept for a Sec-WebSocket-Key.
+fn accept_key(key: &str) -> String {
+    STANDARD.encode(Sha1::digest(format!("{}{}", key, ACCEPT_GUID).as_bytes()))
+}
+
+// Whether the comma-separated `list` contains `token`, ignoring case.
+fn has_token(list: &str, token: &str) -> bool {
+    list.split(',').any(|item| item.trim().eq_ignore_ascii_case(token))
+}
+
+// The handshake response, after which the connection belongs to the handler.
+pub struct SwitchingProtocols {
+    headers: Vec<(String, String)>,
+    handler: Handler,
+}
+
+impl Response for SwitchingProtocols {
+    fn status_code(&self) -> u16 {
+        101
+    }
+
+    fn headers_mut(&mut self) -> &mut Vec<(String, String)> {
+        &mut self.headers
+    }
+
+    fn upgrade(&self) -> Option<Handler> {
+        Some(self.handler)
+    }
+
+    fn response_bytes(self: Box<Self>) -> ByteStream {
+        Box::new(futures::stream::iter(vec![Ok(response::head("101 Switching Protocols", &self.headers))]))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+// What a frame from the client came to.
+pub enum Received {
+    Message(Message),
+    // A control frame, which has been dealt with, or part of a message.
+    Partial,
+    Closed,
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+/*
+* A WebSocket connection, from the server's end. Pings are answered and fragmented messages put
+* back together by receive(); anything against the protocol closes the connection with the
+* appropriate status code.
+*/
+pub struct WebSocket {
+    // Anything the client sent after the handshake may already be buffered here.
+    reader: PeekableBufReader<TcpStream>,
+    writer: TcpStream,
+    // The opcode and payload so far of a fragmented message.
+    fragments: Option<(u8, Vec<u8>)>,
+    closed: bool,
+}
+
+impl WebSocket {
+    pub fn new(mut reader: PeekableBufReader<TcpStream>, writer: TcpStream) -> Self {
+        // The parser's limit on the header section doesn't apply to the frames that follow it.
+        reader.set_limit(usize::MAX);
+        Self {
+            reader,
+            writer,
+            fragments: None,
+            closed: false,
+        }
+    }
+
+    /*
+    * Wait until the client has sent something, without reading it, so that this can be raced
+    * against other events and dropped without losing part of a frame. False once it's hung up.
+    */
+    pub async fn readable(&mut self) -> bool {
+        self.reader.peek().await.is_some()
+    }
+
+    // The next message, or None once the connection is closed.
+    pub async fn receive(&mut self) -> io::Result<Option<Message>> {
+        loop {
+            match self.receive_frame().await? {
+                Received::Message(message) => return Ok(Some(message)),
+                Received::Partial => {},
+                Received::Closed => return Ok(None),
+            }
+        }
+    }
+
+    // Read and deal with a single frame, for handlers that only care about the connection staying up.
+    pub async fn receive_frame(&mut self) -> io::Result<Received> {
+        if self.closed {
+            return Ok(Received::Closed);
+        }
+        let frame = match self.read_frame().await? {
+            Some(frame) => frame,
+            None => return Ok(Received::Closed),
+        };
+        match frame.opcode {
+            OPCODE_PING => self.write_frame(OPCODE_PONG, &frame.payload).await?,
+            OPCODE_PONG => {},
+            OPCODE_CLOSE => {
+                /* RFC 6455, Page 36: echo the status code back, and then we're done. */
+                self.write_frame(OPCODE_CLOSE, &frame.payload[..frame.payload.len().min(2)]).await?;
+                self.closed = true;
+                return Ok(Received::Closed);
+            },
+            OPCODE_CONTINUATION => match self.fragments.as_mut() {
+                Some((_, payload)) => payload.extend_from_slice(&frame.payload),
+                None => return self.fail(CLOSE_PROTOCOL_ERROR).await,
+            },
+            OPCODE_TEXT | OPCODE_BINARY if self.fragments.is_none() => self.fragments = Some((frame.opcode, frame.payload)),
+            _ => return self.fail(CLOSE_PROTOCOL_ERROR).await,
+        }
+        if self.fragments.as_ref().is_some_and(|(_, payload)| payload.len() as u64 > MAX_MESSAGE_LENGTH) {
+            return self.fail(CLOSE_TOO_BIG).await;
+        }
+        // Control frames can come between fragments, but never end a message.
+        if !frame.fin || frame.opcode & 0x8 != 0 {
+            return Ok(Received::Partial);
+        }
+        match self.fragments.take() {
+            Some((OPCODE_TEXT, payload)) => match String::from_utf8(payload) {
+                Ok(text) => Ok(Received::Message(Message::Text(text))),
+                Err(_) => self.fail(CLOSE_INVALID_DATA).await,
+            },
+            Some((_, payload)) => Ok(Received::Message(Message::Binary(payload))),
+            None => Ok(Received::Partial),
+        }
+    }
+
+    // Send `message` in a single frame.
+    pub async fn send(&mut self, message: &Message) -> io::Result<()> {
+        match message {
+            Message::Text(text) => self.write_frame(OPCODE_TEXT, text.as_bytes()).await,
+            Message::Binary(data) => self.write_frame(OPCODE_BINARY, data).await,
+        }
+    }
+
+    // Start the closing handshake with `code`. Nothing more can be received after this.
+    pub async fn close(&mut self, code: u16) -> io::Result<()> {
+        if !self.closed {
+            self.closed = true;
+            self.write_frame(OPCODE_CLOSE, &code.to_be_bytes()).await?;
+        }
+        Ok(())
+    }
+
+    async fn fail(&mut self, code: u16) -> io::Result<Received> {
+        self.close(code).await?;
+        Ok(Received::Closed)
+    }
+
+    /*
+    * RFC 6455, Page 28
+    * The next frame, unmasked. None if the client hung up between frames, or sent something
+    * we had to close the connection over.
+    */
+    async fn read_frame(&mut self) -> io::Result<Option<Frame>> {
+        let mut head = [0; 2];
+        if self.reader.read(&mut head[..1]).await? == 0 {
+            return Ok(None);
+        }
+        self.read_exact(&mut head[1..]).await?;
+        let fin = head[0] & 0x80 != 0;
+        let opcode = head[0] & 0x0F;
+        // No extensions were negotiated, so the reserved bits have to be clear. Clients always mask.
+        if head[0] & 0x70 != 0 || head[1] & 0x80 == 0 {
+            self.close(CLOSE_PROTOCOL_ERROR).await?;
+            return Ok(None);
+        }
+        let length = match head[1] & 0x7F {
+            126 => {
+                let mut length = [0; 2];
+                self.read_exact(&mut length).await?;
+                u16::from_be_bytes(length) as u64
+            },
+            127 => {
+                let mut length = [0; 8];
+                self.read_exact(&mut length).await?;
+                u64::from_be_bytes(length)
+            },
+            length => length as u64,
+        };
+        /* RFC 6455, Page 36: control frames are short and can't be fragmented. */
+        if opcode & 0x8 != 0 && (length > 125 || !fin) {
+            self.close(CLOSE_PROTOCOL_ERROR).await?;
+            return Ok(None);
+        }
+        if length > MAX_MESSAGE_LENGTH {
+            self.close(CLOSE_TOO_BIG).await?;
+            return Ok(None);
+        }
+        let mut mask = [0; 4];
+        self.read_exact(&mut mask).await?;
+        let mut payload = vec![0; length as usize];
+        self.read_exact(&mut payload).await?;
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+        Ok(Some(Frame { fin, opcode, payload }))
+    }
+
+    async fn read_exact(&mut self, buffer: &mut [u8]) -> io::Result<()> {
+        let mut filled = 0;
+        while filled < buffer.len() {
+            match self.reader.read(&mut buffer[filled..]).await? {
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                size => filled += size,
+            }
+        }
+        Ok(())
+    }
+
+    // A single, final frame. Servers don't mask.
+    async fn write_frame(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        let mut frame = vec![0x80 | opcode];
+        match payload.len() {
+            length if length < 126 => frame.push(length as u8),
+            length if length <= u16::MAX as usize => {
+                frame.push(126);
+                frame.extend_from_slice(&(length as u16).to_be_bytes());
+            },
+            length => {
+                frame.push(127);
+                frame.extend_from_slice(&(length as u64).to_be_bytes());
+            },
+        }
+        frame.extend_from_slice(payload);
+        self.writer.write_all(&frame).await?;
+        self.writer.flush().await
+    }
+}
+
+// Send every message back as it came, for trying out clients.
+pub fn echo(mut socket: WebSocket, _: Arc<Config>, _: PathBuf) -> BoxFuture<'static, io::Result<()>> {
+    Box::pin(async move {
+        while let Some(message) = socket.receive().await? {
+            socket.send(&message).await?;
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::io::BufReader;
+    use async_std::net::TcpListener;
+    use async_std::task;
+
+    use super::*;
+    use crate::http::Parser;
+
+    // A masked frame from the client.
+    fn frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [1, 2, 3, 4];
+        let mut frame = vec![0x80 | opcode, 0x80 | 126];
+        frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+        frame
+    }
+
+    /* RFC 6455, Page 8 */
+    #[test]
+    fn the_accept_key_is_the_rfc_example() {
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn frames_can_go_past_the_header_limit() {
+        task::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut message = b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n".to_vec();
+            for i in 0..64 {
+                message.extend(frame(OPCODE_TEXT, format!("{:01000}", i).as_bytes()));
+            }
+            client.write_all(&message).await.unwrap();
+            let mut parser = Parser::new(PeekableBufReader::new(BufReader::new(stream.clone())));
+            parser.parse().await.expect("the request should parse");
+            let mut socket = WebSocket::new(parser.into_inner(), stream);
+            for i in 0..64 {
+                assert!(socket.readable().await);
+                match socket.receive().await.unwrap() {
+                    Some(Message::Text(text)) => assert_eq!(text, format!("{:01000}", i)),
+                    _ => panic!("message {} should have been received", i),
+                }
+            }
+        });
+    }
+}